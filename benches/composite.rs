@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use pixel_editor::app::Color;
+use pixel_editor::depth::BitDepth;
+use pixel_editor::fill::{self, ColorDistance};
+use pixel_editor::layer::{Image, Layer, ImageRect, TRANSPARENT};
+
+/// A 4096x4096 image with three half transparent, partly overlapping layers
+/// over the background.
+fn layered_image() -> Image {
+    let mut image = Image::new(4096, 4096);
+    for i in 0..3 {
+        let mut layer = Layer::new(ImageRect::new(i * 512, i * 512, 3072, 3072));
+        layer.data.fill(TRANSPARENT);
+        for x in (0..3072).step_by(3) {
+            layer.draw_line(x, 0, 3071 - x, 3071, Color::from_rgba(200, 40 * i as u8, 90, 128));
+        }
+        image.layers.push(layer);
+    }
+    image
+}
+
+fn composite_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("composite 4096x4096");
+    group.sample_size(10);
+
+    let image = layered_image();
+    let mut deep = layered_image();
+    deep.set_bit_depth(BitDepth::Sixteen);
+    let layer = &image.layers[1];
+
+    // The same work on one thread and on every core shows the speedup
+    let mut threads = vec![1, std::thread::available_parallelism().map_or(1, |n| n.get())];
+    threads.dedup();
+    for n in threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(n).build().unwrap();
+        group.bench_function(format!("blend {} threads", n), |b| b.iter(|| pool.install(|| image.blend(image.rect))));
+        group.bench_function(format!("raw_data {} threads", n), |b| b.iter(|| pool.install(|| image.raw_data())));
+        group.bench_function(format!("flatten 16-bit {} threads", n), |b| b.iter(|| pool.install(|| deep.flatten_16bit([0; 4]))));
+        group.bench_function(format!("replace color {} threads", n), |b| b.iter(|| pool.install(|| {
+            let mut layer = layer.clone();
+            fill::replace_color(&mut layer, Color::from_rgba(200, 40, 90, 128), Color::from_rgba(0, 0, 0, 255), 0.2, ColorDistance::Perceptual, None)
+        })));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, composite_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use pixel_editor::app;
+use pixel_editor::fill::{self, FillOptions};
+use pixel_editor::layer::{Layer, ImageRect};
+
+fn fill_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fill 4096x4096");
+    group.sample_size(10);
+
+    // An empty layer, where the fill covers everything
+    let empty = Layer::new(ImageRect::new(0, 0, 4096, 4096));
+
+    // Walls with gaps at alternating ends, so the fill has to snake back and
+    // forth through the whole layer
+    let mut maze = Layer::new(ImageRect::new(0, 0, 4096, 4096));
+    for (i, x) in (4..4096).step_by(8).enumerate() {
+        if i % 2 == 0 {
+            maze.draw_line(x, 0, x, 4094, app::BLACK);
+        } else {
+            maze.draw_line(x, 1, x, 4095, app::BLACK);
+        }
+    }
+
+    let options = FillOptions::default();
+    for (name, layer) in &[("empty", &empty), ("maze", &maze)] {
+        group.bench_function(format!("scanline {}", name), |b| b.iter(|| fill::find_region(layer, 1, 1, &options)));
+        group.bench_function(format!("queue {}", name), |b| b.iter(|| fill::find_region_queue(layer, 1, 1, &options)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fill_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use pixel_editor::app::{self, Color};
+use pixel_editor::layer::{Image, Layer, ImageRect, TRANSPARENT};
+
+const SIZES: [u32; 3] = [256, 1024, 4096];
+
+/// A `size` square image with a second, half transparent layer covering it,
+/// striped so blending has real work on every tile.
+fn two_layer_image(size: u32) -> Image {
+    let mut image = Image::new(size, size);
+    let mut layer = Layer::new(image.rect);
+    layer.data.fill(TRANSPARENT);
+    for x in (0..size as i32).step_by(4) {
+        layer.draw_line(x, 0, x, size as i32 - 1, Color::from_rgba(30, 120, 200, 128));
+    }
+    image.layers.push(layer);
+    image
+}
+
+fn fill_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Layer::fill");
+    group.sample_size(10);
+    for size in SIZES {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_batched_ref(
+                || Layer::new(ImageRect::new(0, 0, size, size)),
+                |layer| layer.fill(0, 0, app::BLACK),
+                criterion::BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn draw_line_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Layer::draw_line");
+    for size in SIZES {
+        let mut layer = Layer::new(ImageRect::new(0, 0, size, size));
+        let end = size as i32 - 1;
+        group.bench_with_input(BenchmarkId::from_parameter(size), &end, |b, &end| {
+            b.iter(|| layer.draw_line(0, 0, end, end / 3, app::BLACK));
+        });
+    }
+    group.finish();
+}
+
+fn blend_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Layer::blend");
+    group.sample_size(10);
+    for size in SIZES {
+        let image = two_layer_image(size);
+        let mut top = image.layers[1].clone();
+        top.add_dirty_rect(image.rect);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &image.rect, |b, &rect| {
+            b.iter_batched_ref(
+                || Layer::new(rect),
+                |base| base.blend(&top, rect),
+                criterion::BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn image_data_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Image data");
+    group.sample_size(10);
+    for size in SIZES {
+        let image = two_layer_image(size);
+        // A brush-sized dirty rect in the middle of the image
+        let dirty = ImageRect::new(size as i32 / 2 - 32, size as i32 / 2 - 32, 64, 64);
+        group.bench_with_input(BenchmarkId::new("partial_data", size), &dirty, |b, &dirty| {
+            b.iter(|| image.partial_data(dirty));
+        });
+        group.bench_with_input(BenchmarkId::new("raw_data", size), &image, |b, image| {
+            b.iter(|| image.raw_data());
+        });
+    }
+    group.finish();
+}
+
+fn save_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Image::save");
+    group.sample_size(10);
+    let path = std::env::temp_dir().join("pixel_editor_save_bench.png");
+    for size in SIZES {
+        let image = two_layer_image(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &image, |b, image| {
+            b.iter(|| image.save(&path).unwrap());
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, fill_benchmark, draw_line_benchmark, blend_benchmark, image_data_benchmark, save_benchmark);
+criterion_main!(benches);
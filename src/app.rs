@@ -1,7 +1,13 @@
+#[cfg(feature = "gui")]
 pub use macroquad::prelude::*;
+#[cfg(feature = "gui")]
 pub use macroquad::prelude::KeyCode as Key;
+#[cfg(feature = "gui")]
 pub use macroquad::prelude::MouseButton as Button;
 
+#[cfg(not(feature = "gui"))]
+pub use super::color::*;
+
 #[macro_export]
 macro_rules! rect(
     ($x:expr, $y:expr, $w:expr, $h:expr $(,)?) => (
@@ -38,45 +44,57 @@ macro_rules! color(
     );
 );
 
+#[cfg(feature = "gui")]
 pub fn draw_rect(rect: Rect, color: Color) {
     draw_rectangle(rect.x, rect.y, rect.w, rect.h, color);
 }
 
+#[cfg(feature = "gui")]
 pub fn is_mouse_left_down() -> bool {
     is_mouse_button_down(MouseButton::Left)
 }
+#[cfg(feature = "gui")]
 pub fn is_mouse_right_down() -> bool {
     is_mouse_button_down(MouseButton::Right)
 }
+#[cfg(feature = "gui")]
 pub fn is_mouse_middle_down() -> bool {
     is_mouse_button_down(MouseButton::Middle)
 }
+#[cfg(feature = "gui")]
 pub fn is_mouse_left_pressed() -> bool {
     is_mouse_button_pressed(MouseButton::Left)
 }
+#[cfg(feature = "gui")]
 pub fn is_mouse_right_pressed() -> bool {
     is_mouse_button_pressed(MouseButton::Right)
 }
+#[cfg(feature = "gui")]
 pub fn is_mouse_middle_pressed() -> bool {
     is_mouse_button_pressed(MouseButton::Middle)
 }
 
+#[cfg(feature = "gui")]
 pub fn is_ctrl_down() -> bool {
     is_key_down(Key::LeftControl) || is_key_down(Key::RightControl)
 }
 
+#[cfg(feature = "gui")]
 pub fn is_alt_down() -> bool {
     is_key_down(Key::LeftAlt) || is_key_down(Key::RightAlt)
 }
 
+#[cfg(feature = "gui")]
 pub fn is_shift_down() -> bool {
     is_key_down(Key::LeftShift) || is_key_down(Key::RightShift)
 }
 
+#[cfg(feature = "gui")]
 pub fn is_super_down() -> bool {
     is_key_down(Key::LeftSuper) || is_key_down(Key::RightSuper)
 }
 
+#[cfg(feature = "gui")]
 fn is_printable(c: char) -> bool {
     // Check if it's a printable ASCII character
     if c.is_ascii() {
@@ -104,6 +122,7 @@ fn is_printable(c: char) -> bool {
     }
 }
 
+#[cfg(feature = "gui")]
 pub fn get_text() -> Option<String> {
     let mut text = None;
     while let Some(c) = get_char_pressed() {
@@ -0,0 +1,284 @@
+//! Processes images from the command line without opening a window, for build
+//! pipelines. Only the editor's image code is used, so this builds without
+//! the `gui` feature and macroquad:
+//!
+//!     cargo build --bin pixel_batch --no-default-features
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use pixel_editor::layer::{Image, TRANSPARENT};
+use pixel_editor::palette::Palette;
+use pixel_editor::quantize::{self, Dither};
+use pixel_editor::transform::{self, Flip};
+
+const USAGE: &str = "\
+Usage: pixel_batch [OPERATION...] INPUT... -o OUTPUT
+
+Operations are applied to every input, in the order given:
+  --resize WxH       scale with nearest neighbor sampling
+  --flip h|v         mirror horizontally or vertically
+  --dither MODE      none, floyd-steinberg or ordered, for the --remap after it
+  --remap PALETTE    snap colors to a .gpl, .hex, .pal or .aco palette
+  --flatten          merge the layers into one
+
+Output:
+  --sheet COLUMNS    pack the inputs into one sprite sheet, COLUMNS frames wide
+  -o, --output PATH  the file to save, or a directory when there are several
+                     inputs and no --sheet
+  -h, --help         show this message";
+
+enum Operation {
+    Resize(u32, u32),
+    Flip(Flip),
+    Remap(Palette, Dither),
+    Flatten,
+}
+
+struct Options {
+    operations: Vec<Operation>,
+    inputs: Vec<String>,
+    output: PathBuf,
+    sheet_columns: Option<u32>,
+}
+
+fn parse_size(text: &str) -> Result<(u32, u32), String> {
+    let parsed = text.split_once('x').and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+    match parsed {
+        Some((w, h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(format!("invalid size \"{}\", expected WxH", text)),
+    }
+}
+
+/// Parses the arguments after the program name. Returns `None` if help was
+/// asked for.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut operations = Vec::new();
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut sheet_columns = None;
+    let mut dither = Dither::None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--resize" => {
+                let (w, h) = parse_size(&value()?)?;
+                operations.push(Operation::Resize(w, h));
+            }
+            "--flip" => operations.push(Operation::Flip(match value()?.as_str() {
+                "h" => Flip::Horizontal,
+                "v" => Flip::Vertical,
+                other => return Err(format!("invalid flip \"{}\", expected h or v", other)),
+            })),
+            "--dither" => dither = match value()?.as_str() {
+                "none" => Dither::None,
+                "floyd-steinberg" => Dither::FloydSteinberg,
+                "ordered" => Dither::Ordered,
+                other => return Err(format!("unknown dither \"{}\"", other)),
+            },
+            "--remap" => {
+                let path = value()?;
+                let palette = Palette::load(Path::new(&path)).map_err(|e| format!("failed to load {}: {}", path, e))?;
+                operations.push(Operation::Remap(palette, dither));
+            }
+            "--flatten" => operations.push(Operation::Flatten),
+            "--sheet" => {
+                let columns = value()?;
+                match columns.parse() {
+                    Ok(n) if n > 0 => sheet_columns = Some(n),
+                    _ => return Err(format!("invalid column count \"{}\"", columns)),
+                }
+            }
+            "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => inputs.push(arg),
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("no input images".into());
+    }
+    let output = output.ok_or("no output given")?;
+    Ok(Some(Options {
+        operations,
+        inputs,
+        output,
+        sheet_columns,
+    }))
+}
+
+fn apply(image: &mut Image, operation: &Operation) {
+    match operation {
+        Operation::Resize(w, h) => image.resize(*w, *h),
+        Operation::Flip(flip) => image.flip(*flip),
+        Operation::Remap(palette, dither) => {
+            for layer in &mut image.layers {
+                quantize::remap_layer(layer, palette, *dither);
+            }
+        }
+        Operation::Flatten => image.flatten_layers(),
+    }
+}
+
+/// Saves over transparency, unlike the editor, which saves what the canvas
+/// shows over white.
+fn save(image: &Image, path: &Path) -> Result<(), String> {
+    image.save_over(path, TRANSPARENT).map_err(|e| format!("failed to save {}: {}", path.display(), e))
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut images = Vec::new();
+    for input in &options.inputs {
+        let mut image = Image::from_path(input).map_err(|e| format!("failed to open {}: {}", input, e))?;
+        for operation in &options.operations {
+            apply(&mut image, operation);
+        }
+        images.push(image);
+    }
+
+    if let Some(columns) = options.sheet_columns {
+        return save(&transform::sprite_sheet(&images, columns), &options.output);
+    }
+    if let [image] = images.as_slice() {
+        return save(image, &options.output);
+    }
+    fs::create_dir_all(&options.output).map_err(|e| format!("failed to create {}: {}", options.output.display(), e))?;
+    for (input, image) in options.inputs.iter().zip(&images) {
+        let name = Path::new(input).file_name().ok_or_else(|| format!("{} isn't a file", input))?;
+        save(image, &options.output.join(name))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    match parse_args(env::args().skip(1)) {
+        Ok(None) => println!("{}", USAGE),
+        Ok(Some(options)) => {
+            if let Err(e) = run(options) {
+                eprintln!("pixel_batch: {}", e);
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("pixel_batch: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Option<Options>, String> {
+        parse_args(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_operations_in_order() {
+        let options = parse("--resize 64x32 a.png --flip v --flatten b.png --sheet 4 -o sheet.png").unwrap().unwrap();
+        assert_eq!(options.inputs, vec!["a.png", "b.png"]);
+        assert_eq!(options.output, PathBuf::from("sheet.png"));
+        assert_eq!(options.sheet_columns, Some(4));
+        assert!(matches!(options.operations.as_slice(),
+            [Operation::Resize(64, 32), Operation::Flip(Flip::Vertical), Operation::Flatten]));
+        assert!(parse("--help").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse("a.png").is_err());
+        assert!(parse("-o out.png").is_err());
+        assert!(parse("--resize 64 a.png -o out.png").is_err());
+        assert!(parse("--flip x a.png -o out.png").is_err());
+        assert!(parse("--sheet 0 a.png -o out.png").is_err());
+        assert!(parse("--bogus a.png -o out.png").is_err());
+        assert!(parse("a.png -o").is_err());
+    }
+
+    /// A fresh, empty directory for one test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("pixel_batch_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn options(operations: Vec<Operation>, inputs: &[&Path], output: PathBuf, sheet_columns: Option<u32>) -> Options {
+        Options {
+            operations,
+            inputs: inputs.iter().map(|p| p.to_string_lossy().into_owned()).collect(),
+            output,
+            sheet_columns,
+        }
+    }
+
+    #[test]
+    fn saves_keep_transparency() {
+        let dir = test_dir("alpha");
+        let input = dir.join("in.png");
+        let pixels = vec![0, 0, 0, 0, 255, 0, 0, 128, 0, 255, 0, 255, 0, 0, 255, 64];
+        image::RgbaImage::from_raw(2, 2, pixels.clone()).unwrap().save(&input).unwrap();
+
+        let output = dir.join("out.png");
+        run(options(Vec::new(), &[&input], output.clone(), None)).unwrap();
+        assert_eq!(image::open(&output).unwrap().to_rgba8().into_raw(), pixels);
+
+        // Each frame of a sheet keeps its alpha, and the gaps stay clear
+        let sheet = dir.join("sheet.png");
+        run(options(vec![Operation::Flatten], &[&input, &input, &input], sheet.clone(), Some(2))).unwrap();
+        let sheet = image::open(&sheet).unwrap().to_rgba8();
+        assert_eq!(sheet.dimensions(), (4, 4));
+        for (x, y) in [(0, 0), (2, 0), (0, 2)] {
+            let frame = image::imageops::crop_imm(&sheet, x, y, 2, 2).to_image();
+            assert_eq!(frame.into_raw(), pixels);
+        }
+        let unused = image::imageops::crop_imm(&sheet, 2, 2, 2, 2).to_image();
+        assert!(unused.pixels().all(|p| p.0 == [0; 4]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remaps_sixteen_bit_images() {
+        let dir = test_dir("remap16");
+        let input = dir.join("in.png");
+        let pixels = vec![13000, 13000, 13000, 65535, 60000, 60000, 60000, 65535];
+        image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(2, 1, pixels).unwrap().save(&input).unwrap();
+        let palette = dir.join("bw.hex");
+        fs::write(&palette, "000000\nffffff\n").unwrap();
+
+        let output = dir.join("out.png");
+        let palette = Palette::load(&palette).unwrap();
+        run(options(vec![Operation::Remap(palette, Dither::None)], &[&input], output.clone(), None)).unwrap();
+        let remapped = image::open(&output).unwrap();
+        assert_eq!(remapped.color(), image::ColorType::Rgba16);
+        assert_eq!(remapped.to_rgba16().into_raw(), vec![0, 0, 0, 65535, 65535, 65535, 65535, 65535]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sheets_with_sixteen_bit_frames_are_sixteen_bit() {
+        let dir = test_dir("sheet16");
+        let deep = dir.join("deep.png");
+        let pixels = vec![13000, 26000, 39000, 65535];
+        image::ImageBuffer::<image::Rgba<u16>, _>::from_raw(1, 1, pixels.clone()).unwrap().save(&deep).unwrap();
+        let narrow = dir.join("narrow.png");
+        image::RgbaImage::from_raw(1, 1, vec![255, 0, 0, 255]).unwrap().save(&narrow).unwrap();
+
+        let output = dir.join("sheet.png");
+        run(options(Vec::new(), &[&deep, &narrow, &deep], output.clone(), Some(2))).unwrap();
+        let sheet = image::open(&output).unwrap();
+        assert_eq!(sheet.color(), image::ColorType::Rgba16);
+        let sheet = sheet.to_rgba16();
+        assert_eq!(sheet.dimensions(), (2, 2));
+        assert_eq!(sheet.get_pixel(0, 0).0.to_vec(), pixels);
+        assert_eq!(sheet.get_pixel(1, 0).0, [65535, 0, 0, 65535]);
+        assert_eq!(sheet.get_pixel(0, 1).0.to_vec(), pixels);
+        assert_eq!(sheet.get_pixel(1, 1).0, [0; 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
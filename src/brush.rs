@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::RgbaImage;
+use image::error::ImageError;
+
+use super::app::Color;
+use super::layer::{self, Layer, ImageRect};
+use super::util;
+
+/// A brush tip image. Stamps paint with their own colors rather than the
+/// active color, unless the brush is set to recolor them.
+#[derive(Clone)]
+pub struct Stamp {
+    pub w: u32,
+    pub h: u32,
+    pub data: Vec<Color>,
+}
+
+impl Stamp {
+    pub fn from_path(path: &str) -> Result<Self, ImageError> {
+        let layer = Layer::from_path(0, 0, path)?;
+        Ok(Self {
+            w: layer.rect.w,
+            h: layer.rect.h,
+            data: layer.data.iter().map(|&p| layer::from_rgba8(p)).collect(),
+        })
+    }
+
+    /// Copies the pixels of `layer` inside `rect`, given in layer coordinates.
+    /// Returns `None` if the rect doesn't overlap the layer.
+    pub fn from_layer(layer: &Layer, rect: ImageRect) -> Option<Self> {
+        let rect = rect.intersection(ImageRect::new(0, 0, layer.rect.w, layer.rect.h));
+        if rect.w == 0 || rect.h == 0 {
+            return None;
+        }
+        let mut data = Vec::with_capacity((rect.w * rect.h) as usize);
+        for y in rect.y..rect.y + rect.h as i32 {
+            for x in rect.x..rect.x + rect.w as i32 {
+                data.push(layer.get_pixel_unchecked(x, y));
+            }
+        }
+        Some(Self {
+            w: rect.w,
+            h: rect.h,
+            data,
+        })
+    }
+
+    /// The color at (x, y) when the stamp is repeated infinitely in every
+    /// direction from the origin.
+    pub fn tile(&self, x: i32, y: i32) -> Color {
+        let tx = x.rem_euclid(self.w as i32) as u32;
+        let ty = y.rem_euclid(self.h as i32) as u32;
+        self.data[(ty * self.w + tx) as usize]
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ImageError> {
+        let mut image = RgbaImage::new(self.w, self.h);
+        for (i, color) in self.data.iter().enumerate() {
+            image.put_pixel(i as u32 % self.w, i as u32 / self.w, util::color_to_rgba8(*color).into());
+        }
+        image.save(path)
+    }
+}
+
+/// Custom brushes, stored as PNG files in a directory on disk.
+pub struct BrushLibrary {
+    pub dir: PathBuf,
+    pub brushes: Vec<(String, Stamp)>,
+}
+
+impl BrushLibrary {
+    /// Loads every PNG in `dir`. A missing directory is treated as an empty
+    /// library and is only created once a brush is added.
+    pub fn load(dir: &Path) -> Self {
+        let mut brushes = Vec::new();
+        if let Ok(entries) = fs::read_dir(dir) {
+            let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            paths.sort();
+            for path in paths {
+                if path.extension().and_then(|ext| ext.to_str()) != Some("png") {
+                    continue;
+                }
+                let name = match path.file_stem() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => continue,
+                };
+                if let Ok(stamp) = Stamp::from_path(&path.to_string_lossy()) {
+                    brushes.push((name, stamp));
+                }
+            }
+        }
+        Self {
+            dir: dir.to_path_buf(),
+            brushes,
+        }
+    }
+
+    /// Adds a brush under the next free "brush_N" name and writes it to disk.
+    pub fn add(&mut self, stamp: Stamp) -> Result<(), ImageError> {
+        let mut n = self.brushes.len() + 1;
+        let mut name = format!("brush_{}", n);
+        while self.brushes.iter().any(|(existing, _)| *existing == name) {
+            n += 1;
+            name = format!("brush_{}", n);
+        }
+        fs::create_dir_all(&self.dir).map_err(ImageError::IoError)?;
+        stamp.save(&self.dir.join(format!("{}.png", name)))?;
+        self.brushes.push((name, stamp));
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub enum BrushShape {
+    Round,
+    Square,
+    Stamp(Stamp),
+}
+
+#[derive(Clone)]
+pub struct Brush {
+    /// Diameter in pixels. Ignored by stamps, which paint at their own size.
+    pub size: u32,
+    pub shape: BrushShape,
+    /// Fraction of the radius painted at full strength before the edge starts
+    /// to fade out.
+    pub hardness: f32,
+    /// Distance between stamps along the stroke, as a fraction of the size.
+    pub spacing: f32,
+    pub opacity: f32,
+    /// Paint stamps with the active color, using only their alpha.
+    pub recolor: bool,
+}
+
+/// The coverage (and, for stamps, the colors) of a brush rendered out to
+/// pixels.
+pub struct BrushTip {
+    pub w: u32,
+    pub h: u32,
+    pub coverage: Vec<f32>,
+    pub colors: Option<Vec<Color>>,
+}
+
+impl Brush {
+    pub fn new() -> Self {
+        Self {
+            size: 20,
+            shape: BrushShape::Round,
+            hardness: 1.0,
+            spacing: 0.25,
+            opacity: 1.0,
+            recolor: false,
+        }
+    }
+
+    pub fn tip(&self) -> BrushTip {
+        match &self.shape {
+            BrushShape::Stamp(stamp) if self.recolor => BrushTip {
+                w: stamp.w,
+                h: stamp.h,
+                coverage: stamp.data.iter().map(|c| c.a).collect(),
+                colors: None,
+            },
+            BrushShape::Stamp(stamp) => BrushTip {
+                w: stamp.w,
+                h: stamp.h,
+                coverage: vec![1.0; stamp.data.len()],
+                colors: Some(stamp.data.clone()),
+            },
+            shape => {
+                let size = self.size.max(1);
+                let radius = size as f32 / 2.0;
+                let mut coverage = vec![0.0; (size * size) as usize];
+                for y in 0..size {
+                    for x in 0..size {
+                        let dx = (x as f32 + 0.5 - radius) / radius;
+                        let dy = (y as f32 + 0.5 - radius) / radius;
+                        let d = match shape {
+                            BrushShape::Square => dx.abs().max(dy.abs()),
+                            _ => (dx * dx + dy * dy).sqrt(),
+                        };
+                        coverage[(y * size + x) as usize] = if d > 1.0 {
+                            0.0
+                        } else if d <= self.hardness || self.hardness >= 1.0 {
+                            1.0
+                        } else {
+                            (1.0 - d) / (1.0 - self.hardness)
+                        };
+                    }
+                }
+                BrushTip {
+                    w: size,
+                    h: size,
+                    coverage,
+                    colors: None,
+                }
+            }
+        }
+    }
+
+    /// Distance in pixels between consecutive stamps.
+    pub fn step(&self) -> f32 {
+        let size = match &self.shape {
+            BrushShape::Stamp(stamp) => stamp.w.max(stamp.h),
+            _ => self.size,
+        };
+        (size as f32 * self.spacing).max(1.0)
+    }
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of a brush stroke in progress. Each pixel keeps the color it had
+/// before the stroke started, so overlapping stamps never build up past the
+/// brush opacity.
+#[derive(Default)]
+pub struct BrushStroke {
+    tip: Option<BrushTip>,
+    step: f32,
+    opacity: f32,
+    distance: f32,
+    started: bool,
+    touched: HashMap<(i32, i32), (Color, f32)>,
+}
+
+impl BrushStroke {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new stroke with the current brush settings.
+    pub fn begin(&mut self, brush: &Brush) {
+        self.tip = Some(brush.tip());
+        self.step = brush.step();
+        self.opacity = brush.opacity;
+        self.distance = 0.0;
+        self.started = false;
+        self.touched.clear();
+    }
+
+    /// Stamps the brush along the line from (x1, y1) to (x2, y2). The first
+    /// stamp of a stroke lands on (x1, y1); after that, stamps are placed
+    /// every `Brush::step` pixels, carrying the remainder across calls.
+    pub fn draw_line(&mut self, layer: &mut Layer, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+        if self.tip.is_none() {
+            return;
+        }
+        if !self.started {
+            self.started = true;
+            self.stamp(layer, x1, y1, color);
+        }
+
+        let dx = (x2 - x1) as f32;
+        let dy = (y2 - y1) as f32;
+        let length = (dx * dx + dy * dy).sqrt();
+        if length == 0.0 {
+            return;
+        }
+
+        let mut t = self.step - self.distance;
+        while t <= length {
+            let x = x1 as f32 + dx * t / length;
+            let y = y1 as f32 + dy * t / length;
+            self.stamp(layer, x.round() as i32, y.round() as i32, color);
+            t += self.step;
+        }
+        self.distance = length - (t - self.step);
+    }
+
+    fn stamp(&mut self, layer: &mut Layer, x: i32, y: i32, color: Color) {
+        let tip = match &self.tip {
+            Some(tip) => tip,
+            None => return,
+        };
+        let left = x - tip.w as i32 / 2;
+        let top = y - tip.h as i32 / 2;
+
+        for ty in 0..tip.h {
+            for tx in 0..tip.w {
+                let i = (ty * tip.w + tx) as usize;
+                let coverage = tip.coverage[i] * self.opacity;
+                if coverage <= 0.0 {
+                    continue;
+                }
+                let (px, py) = (left + tx as i32, top + ty as i32);
+                let base = match layer.get_pixel(px, py) {
+                    Some(base) => base,
+                    None => continue,
+                };
+                let entry = self.touched.entry((px, py)).or_insert((base, 0.0));
+                if coverage <= entry.1 {
+                    continue;
+                }
+                entry.1 = coverage;
+                let src = match &tip.colors {
+                    Some(colors) => colors[i],
+                    None => color,
+                };
+                layer.draw_pixel(px, py, layer::composite(entry.0, src, coverage));
+            }
+        }
+
+        layer.add_dirty_rect(ImageRect::new(left - 1, top - 1, tip.w + 2, tip.h + 2));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app;
+
+    fn brush(shape: BrushShape, size: u32, hardness: f32) -> Brush {
+        Brush {
+            shape,
+            size,
+            hardness,
+            ..Brush::new()
+        }
+    }
+
+    /// The x of every pixel in row `y` that was painted over the white layer.
+    fn painted_columns(layer: &Layer, y: i32) -> Vec<i32> {
+        (0..layer.rect.w as i32).filter(|&x| layer.get_pixel(x, y) != Some(app::WHITE)).collect()
+    }
+
+    #[test]
+    fn library_names_saves_and_reloads_brushes() {
+        let dir = std::env::temp_dir().join("pixel_editor_brush_library_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut library = BrushLibrary::load(&dir);
+        assert!(library.brushes.is_empty());
+        assert!(!dir.exists());
+
+        let gray = Color::from_rgba(128, 128, 128, 255);
+        let red = Color::from_rgba(255, 0, 0, 128);
+        library.add(Stamp { w: 1, h: 1, data: vec![gray] }).unwrap();
+        library.add(Stamp { w: 2, h: 1, data: vec![red, gray] }).unwrap();
+        fs::write(dir.join("notes.txt"), "not a brush").unwrap();
+
+        let mut library = BrushLibrary::load(&dir);
+        let names: Vec<&str> = library.brushes.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["brush_1", "brush_2"]);
+        let stamp = &library.brushes[1].1;
+        assert_eq!((stamp.w, stamp.h), (2, 1));
+        assert!(stamp.data == vec![red, gray]);
+
+        // With brush_1 gone the next number is taken, so the name skips ahead
+        fs::remove_file(dir.join("brush_1.png")).unwrap();
+        library = BrushLibrary::load(&dir);
+        library.add(Stamp { w: 1, h: 1, data: vec![red] }).unwrap();
+        let names: Vec<&str> = library.brushes.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["brush_2", "brush_3"]);
+        assert!(dir.join("brush_3.png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tips_cover_their_shapes() {
+        let square = brush(BrushShape::Square, 4, 1.0).tip();
+        assert_eq!((square.w, square.h), (4, 4));
+        assert!(square.coverage.iter().all(|&c| c == 1.0));
+
+        // The corners of a round tip fall outside the circle
+        let round = brush(BrushShape::Round, 4, 1.0).tip();
+        assert_eq!(round.coverage, vec![
+            0.0, 1.0, 1.0, 0.0,
+            1.0, 1.0, 1.0, 1.0,
+            1.0, 1.0, 1.0, 1.0,
+            0.0, 1.0, 1.0, 0.0,
+        ]);
+
+        // A soft tip fades out from the center
+        let soft = brush(BrushShape::Round, 4, 0.0).tip();
+        let (center, edge) = (soft.coverage[5], soft.coverage[1]);
+        assert!(center < 1.0 && edge < center && edge > 0.0);
+        assert_eq!(soft.coverage[0], 0.0);
+
+        let red = Color::from_rgba(255, 0, 0, 255);
+        let clear = Color::from_rgba(0, 0, 0, 0);
+        let stamp = Stamp { w: 2, h: 1, data: vec![red, clear] };
+        let tip = brush(BrushShape::Stamp(stamp.clone()), 20, 1.0).tip();
+        assert_eq!((tip.w, tip.h), (2, 1));
+        assert!(tip.colors.is_some());
+        let recolored = Brush { recolor: true, ..brush(BrushShape::Stamp(stamp), 20, 1.0) }.tip();
+        assert_eq!(recolored.coverage, vec![1.0, 0.0]);
+        assert!(recolored.colors.is_none());
+    }
+
+    #[test]
+    fn spacing_carries_over_between_segments() {
+        let brush = Brush { spacing: 4.0, ..brush(BrushShape::Square, 1, 1.0) };
+        let mut stroke = BrushStroke::new();
+
+        let mut whole = Layer::new(ImageRect::new(0, 0, 12, 1));
+        stroke.begin(&brush);
+        stroke.draw_line(&mut whole, 0, 0, 10, 0, app::BLACK);
+        assert_eq!(painted_columns(&whole, 0), vec![0, 4, 8]);
+
+        let mut pieces = Layer::new(ImageRect::new(0, 0, 12, 1));
+        stroke.begin(&brush);
+        for (x1, x2) in [(0, 3), (3, 5), (5, 5), (5, 10)] {
+            stroke.draw_line(&mut pieces, x1, 0, x2, 0, app::BLACK);
+        }
+        assert_eq!(painted_columns(&pieces, 0), vec![0, 4, 8]);
+    }
+
+    #[test]
+    fn overlapping_dabs_stay_under_the_stroke_opacity() {
+        let brush = Brush { opacity: 0.5, spacing: 0.1, ..brush(BrushShape::Round, 6, 0.5) };
+        let mut layer = Layer::new(ImageRect::new(0, 0, 20, 20));
+        let mut stroke = BrushStroke::new();
+        stroke.begin(&brush);
+        // Back and forth over the same pixels, with dabs a pixel apart
+        stroke.draw_line(&mut layer, 4, 10, 16, 10, app::BLACK);
+        stroke.draw_line(&mut layer, 16, 10, 4, 10, app::BLACK);
+        stroke.draw_line(&mut layer, 4, 10, 16, 10, app::BLACK);
+
+        let half = layer::to_rgba8(layer::composite(app::WHITE, app::BLACK, 0.5));
+        let darkest = layer.data.iter().map(|p| p[0]).min().unwrap();
+        assert_eq!(darkest, half[0]);
+
+        // A new stroke starts over from the current pixels
+        stroke.begin(&brush);
+        stroke.draw_line(&mut layer, 10, 10, 10, 10, app::BLACK);
+        assert!(layer.data.get(10, 10)[0] < half[0]);
+    }
+}
@@ -0,0 +1,120 @@
+use std::cmp::min;
+
+use super::app::{self, DrawTextureParams, FilterMode, Texture2D, Vec2};
+use super::composite::CompositeCache;
+use super::layer::ImageRect;
+
+/// The largest side of one texture. `Texture2D` sizes are u16, and GPUs
+/// commonly cap textures well below that anyway.
+pub const TILE_SIZE: u32 = 2048;
+
+struct Tile {
+    rect: ImageRect,
+    texture: Option<Texture2D>,
+    /// The part of the tile, in image coordinates, that changed since it was
+    /// last uploaded.
+    stale: ImageRect,
+}
+
+/// Displays an image as a grid of textures, so canvases can be larger than a
+/// single texture allows. Tiles are only uploaded while they're in view.
+pub struct TiledTexture {
+    tiles: Vec<Tile>,
+}
+
+/// Splits a `w` by `h` image into rects of at most `TILE_SIZE` on a side, row
+/// by row.
+pub fn tile_rects(w: u32, h: u32) -> Vec<ImageRect> {
+    let mut rects = Vec::new();
+    for row in 0..h.div_ceil(TILE_SIZE) {
+        for column in 0..w.div_ceil(TILE_SIZE) {
+            let (x, y) = (column * TILE_SIZE, row * TILE_SIZE);
+            rects.push(ImageRect::new(x as i32, y as i32, min(TILE_SIZE, w - x), min(TILE_SIZE, h - y)));
+        }
+    }
+    rects
+}
+
+impl TiledTexture {
+    pub fn new(w: u32, h: u32) -> Self {
+        let tiles = tile_rects(w, h).into_iter().map(|rect| Tile {
+            rect,
+            texture: None,
+            stale: rect,
+        }).collect();
+        Self {
+            tiles,
+        }
+    }
+
+    /// Marks `rect`, in image coordinates, to be uploaded again.
+    pub fn invalidate(&mut self, rect: ImageRect) {
+        for tile in &mut self.tiles {
+            let changed = tile.rect.intersection(rect);
+            if changed.w != 0 && changed.h != 0 {
+                tile.stale = tile.stale.union(changed);
+            }
+        }
+    }
+
+    /// Uploads the stale parts of the tiles that intersect `viewport`, given
+    /// in image coordinates. Tiles out of view wait until they scroll in.
+    pub fn update(&mut self, composite: &CompositeCache, viewport: ImageRect) {
+        for tile in &mut self.tiles {
+            let visible = tile.rect.intersection(viewport);
+            if visible.w == 0 || visible.h == 0 || tile.stale.w == 0 || tile.stale.h == 0 {
+                continue;
+            }
+            match &tile.texture {
+                Some(texture) => {
+                    let stale = tile.stale;
+                    let part = app::Image {
+                        bytes: composite.partial_data(stale),
+                        width: stale.w as u16,
+                        height: stale.h as u16,
+                    };
+                    texture.update_part(&part, stale.x - tile.rect.x, stale.y - tile.rect.y, stale.w as i32, stale.h as i32);
+                }
+                None => {
+                    let texture = Texture2D::from_rgba8(tile.rect.w as u16, tile.rect.h as u16, &composite.partial_data(tile.rect));
+                    texture.set_filter(FilterMode::Nearest);
+                    tile.texture = Some(texture);
+                }
+            }
+            tile.stale = ImageRect::new(0, 0, 0, 0);
+        }
+    }
+
+    /// Draws the uploaded tiles that intersect `viewport`, with the image's
+    /// top left corner at (x, y) on screen and each pixel `scale` wide.
+    pub fn draw(&self, x: f32, y: f32, scale: f32, viewport: ImageRect) {
+        for tile in &self.tiles {
+            let visible = tile.rect.intersection(viewport);
+            let Some(texture) = &tile.texture else {
+                continue;
+            };
+            if visible.w == 0 || visible.h == 0 {
+                continue;
+            }
+            app::draw_texture_ex(texture, x + tile.rect.x as f32 * scale, y + tile.rect.y as f32 * scale, app::WHITE, DrawTextureParams {
+                dest_size: Some(Vec2::new(tile.rect.w as f32 * scale, tile.rect.h as f32 * scale)),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_image_without_overlap() {
+        let (w, h) = (TILE_SIZE * 2 + 5, TILE_SIZE - 1);
+        let rects = tile_rects(w, h);
+        assert_eq!(rects.len(), 3);
+        assert_eq!(rects.iter().map(|r| r.w as u64 * r.h as u64).sum::<u64>(), w as u64 * h as u64);
+        let last = rects[2];
+        assert_eq!((last.x, last.y, last.w, last.h), (TILE_SIZE as i32 * 2, 0, 5, TILE_SIZE - 1));
+    }
+}
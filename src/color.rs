@@ -0,0 +1,29 @@
+//! `app::Color` for builds without the `gui` feature, so the image code can
+//! be used without linking macroquad. It mirrors macroquad's `Color`, with the
+//! same fields, constructors and constant values, so images come out the same
+//! either way.
+
+/// A color as 4 floats from 0.0 to 1.0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Color {
+    pub const fn new(r: f32, g: f32, b: f32, a: f32) -> Color {
+        Color { r, g, b, a }
+    }
+
+    /// Builds a color from 4 components between 0 and 255.
+    pub const fn from_rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color::new(r as f32 / 255., g as f32 / 255., b as f32 / 255., a as f32 / 255.)
+    }
+}
+
+pub const WHITE: Color = Color::new(1.00, 1.00, 1.00, 1.00);
+pub const BLACK: Color = Color::new(0.00, 0.00, 0.00, 1.00);
+pub const GRAY: Color = Color::new(0.51, 0.51, 0.51, 1.00);
+pub const RED: Color = Color::new(0.90, 0.16, 0.22, 1.00);
@@ -0,0 +1,164 @@
+use super::app;
+use super::layer::{self, Image, ImageRect, Layer, Rgba8, TRANSPARENT};
+
+/// Keeps the composited image between frames so only dirty rects are
+/// re-blended. The layers below and above the active one are cached
+/// separately, so painting on the active layer only blends it between two
+/// cached buffers instead of walking the whole stack.
+pub struct CompositeCache {
+    active: usize,
+    layer_count: usize,
+    /// Every layer below the active one, over the white background.
+    below: Layer,
+    /// Every layer above the active one, over transparency.
+    above: Layer,
+    /// The finished image.
+    composite: Layer,
+}
+
+/// A layer's dirty rect, moved into image coordinates.
+fn image_dirty_rect(layer: &Layer) -> ImageRect {
+    let dirty = layer.dirty_rect;
+    ImageRect::new(dirty.x + layer.rect.x, dirty.y + layer.rect.y, dirty.w, dirty.h)
+}
+
+impl CompositeCache {
+    pub fn new(image: &Image, active: usize) -> Self {
+        let mut above = Layer::new(image.rect);
+        above.data.fill(TRANSPARENT);
+        let mut cache = Self {
+            active,
+            layer_count: image.layers.len(),
+            below: Layer::new(image.rect),
+            above,
+            composite: Layer::new(image.rect),
+        };
+        cache.refresh_below(image, image.rect);
+        cache.refresh_above(image, image.rect);
+        cache.refresh(image, image.rect);
+        cache
+    }
+
+    /// Re-blends whatever the layers' dirty rects cover, and returns the part
+    /// of the image that changed. Call this before clearing the dirty rects.
+    ///
+    /// Adding or removing layers or resizing the image rebuilds everything,
+    /// and changing the active layer rebuilds the cached stacks.
+    pub fn update(&mut self, image: &Image, active: usize) -> ImageRect {
+        let resized = self.composite.rect.w != image.rect.w || self.composite.rect.h != image.rect.h;
+        if resized || self.layer_count != image.layers.len() {
+            *self = Self::new(image, active);
+            return image.rect;
+        }
+
+        let empty = ImageRect::new(0, 0, 0, 0);
+        let (mut below, mut above, mut changed) = (empty, empty, empty);
+        for (i, layer) in image.layers.iter().enumerate() {
+            if layer.dirty_rect.w == 0 || layer.dirty_rect.h == 0 {
+                continue;
+            }
+            let dirty = image_dirty_rect(layer);
+            if i < active {
+                below = below.union(dirty);
+            } else if i > active {
+                above = above.union(dirty);
+            }
+            changed = changed.union(dirty);
+        }
+        if active != self.active {
+            self.active = active;
+            below = image.rect;
+            above = image.rect;
+        }
+
+        self.refresh_below(image, below);
+        self.refresh_above(image, above);
+        let changed = changed.intersection(image.rect);
+        self.refresh(image, changed);
+        changed
+    }
+
+    /// The composited pixels inside `rect`, as RGBA bytes. `rect` must lie
+    /// within the image.
+    pub fn partial_data(&self, rect: ImageRect) -> Vec<u8> {
+        self.composite.data.rect_bytes(rect)
+    }
+
+    /// Sets the pixels of `layer` inside `rect` to `pixel`.
+    fn reset(layer: &mut Layer, rect: ImageRect, pixel: Rgba8) {
+        for y in rect.y..rect.y + rect.h as i32 {
+            for x in rect.x..rect.x + rect.w as i32 {
+                layer.data.set(x as u32, y as u32, pixel);
+            }
+        }
+    }
+
+    fn refresh_below(&mut self, image: &Image, rect: ImageRect) {
+        let rect = rect.intersection(image.rect);
+        Self::reset(&mut self.below, rect, layer::to_rgba8(app::WHITE));
+        for layer in image.layers.iter().take(self.active) {
+            self.below.composite_rect(layer, rect);
+        }
+    }
+
+    fn refresh_above(&mut self, image: &Image, rect: ImageRect) {
+        let rect = rect.intersection(image.rect);
+        Self::reset(&mut self.above, rect, TRANSPARENT);
+        for layer in image.layers.iter().skip(self.active + 1) {
+            self.above.composite_rect(layer, rect);
+        }
+    }
+
+    fn refresh(&mut self, image: &Image, rect: ImageRect) {
+        let below = &self.below;
+        self.composite.data.par_paint(rect, |_| true, |part, tile| {
+            for y in part.y as u32..part.y as u32 + part.h {
+                for x in part.x as u32..part.x as u32 + part.w {
+                    tile.set(x, y, below.data.get(x, y));
+                }
+            }
+        });
+        if let Some(layer) = image.layers.get(self.active) {
+            self.composite.composite_rect(layer, rect);
+        }
+        self.composite.composite_rect(&self.above, rect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app::Color;
+
+    fn three_layer_image() -> Image {
+        let mut image = Image::new(100, 80);
+        for _ in 0..2 {
+            let mut layer = Layer::new(image.rect);
+            layer.data.fill(TRANSPARENT);
+            image.layers.push(layer);
+        }
+        image
+    }
+
+    #[test]
+    fn matches_a_full_blend_after_edits() {
+        let mut image = three_layer_image();
+        let mut cache = CompositeCache::new(&image, 1);
+        let red = Color::from_rgba(255, 0, 0, 255);
+        let green = Color::from_rgba(0, 255, 0, 128);
+
+        image.layers[1].draw_line(0, 0, 99, 79, red);
+        image.layers[2].draw_line(0, 79, 99, 0, green);
+        image.layers[0].draw_line(50, 0, 50, 79, app::BLACK);
+        let changed = cache.update(&image, 1);
+        image.clear_dirty();
+        assert_eq!((changed.x, changed.y, changed.w, changed.h), (0, 0, 100, 80));
+        assert!(cache.partial_data(image.rect) == image.partial_data(image.rect));
+
+        cache.update(&image, 2);
+        image.layers[2].draw_line(10, 10, 20, 10, red);
+        let changed = cache.update(&image, 2);
+        assert_eq!((changed.x, changed.y, changed.w, changed.h), (9, 9, 12, 2));
+        assert!(cache.partial_data(image.rect) == image.partial_data(image.rect));
+    }
+}
@@ -0,0 +1,183 @@
+use image::error::ImageError;
+use image::{ImageBuffer, Rgba};
+use rayon::prelude::*;
+use std::path::Path;
+
+use super::app::Color;
+use super::layer::{Image, Rgba8};
+use super::tiles::TiledPixels;
+
+/// How many bits each channel of the image keeps.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BitDepth {
+    Eight,
+    /// Layers keep 16-bit channels alongside their bytes, and the image saves
+    /// as a 16-bit PNG.
+    Sixteen,
+}
+
+/// A pixel as layers store it in 16-bit mode.
+pub type Rgba16 = [u16; 4];
+
+pub fn to_rgba16(color: Color) -> Rgba16 {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 65535.0).round() as u16;
+    [channel(color.r), channel(color.g), channel(color.b), channel(color.a)]
+}
+
+pub fn from_rgba16(pixel: Rgba16) -> Color {
+    let channel = |c: u16| c as f32 / 65535.0;
+    Color::new(channel(pixel[0]), channel(pixel[1]), channel(pixel[2]), channel(pixel[3]))
+}
+
+/// Scales bytes up to 16 bits, so 255 becomes 65535.
+pub fn widen(pixel: Rgba8) -> Rgba16 {
+    pixel.map(|c| c as u16 * 257)
+}
+
+/// Rounds 16-bit channels to the nearest byte.
+pub fn narrow(pixel: Rgba16) -> Rgba8 {
+    pixel.map(|c| ((c as u32 + 128) / 257) as u8)
+}
+
+/// Composites `color` over `base` with straight alpha, in integer math.
+pub fn composite_rgba16(base: Rgba16, color: Rgba16) -> Rgba16 {
+    let a = color[3] as u64;
+    if a == 65535 {
+        return color;
+    }
+    if a == 0 {
+        return base;
+    }
+    // Both alphas scaled by 65535
+    let base_a = base[3] as u64 * (65535 - a);
+    let out_a = a * 65535 + base_a;
+    let mix = |c: u16, b: u16| ((c as u64 * a * 65535 + b as u64 * base_a + out_a / 2) / out_a) as u16;
+    [
+        mix(color[0], base[0]),
+        mix(color[1], base[1]),
+        mix(color[2], base[2]),
+        ((out_a + 32767) / 65535) as u16,
+    ]
+}
+
+impl Image {
+    pub fn is_16bit(&self) -> bool {
+        self.depth == BitDepth::Sixteen
+    }
+
+    /// Switches between 8 and 16 bits per channel. Going to 16 bits leaves
+    /// indexed mode, and going to 8 bits rounds every channel to a byte.
+    pub fn set_bit_depth(&mut self, depth: BitDepth) {
+        if depth == self.depth {
+            return;
+        }
+        if depth == BitDepth::Sixteen {
+            self.convert_to_rgba();
+        }
+        for layer in &mut self.layers {
+            layer.deep = match depth {
+                BitDepth::Eight => TiledPixels::default(),
+                BitDepth::Sixteen => layer.data.map(widen),
+            };
+        }
+        self.depth = depth;
+    }
+
+    /// Composites every layer over `background` in 16 bits, the same way
+    /// `flatten_over` does in 8 bits.
+    /// Rows are composited in parallel.
+    pub fn flatten_16bit(&self, background: Rgba16) -> Vec<Rgba16> {
+        let mut base = vec![background; self.rect.w as usize * self.rect.h as usize];
+        if base.is_empty() {
+            return base;
+        }
+        base.par_chunks_mut(self.rect.w as usize).enumerate().for_each(|(row, pixels)| {
+            let y = self.rect.y + row as i32;
+            for layer in &self.layers {
+                if y < layer.rect.y || y >= layer.rect.y + layer.rect.h as i32 {
+                    continue;
+                }
+                let span = self.rect.intersection(layer.rect);
+                let layer_y = (y - layer.rect.y) as u32;
+                for x in span.x..span.x + span.w as i32 {
+                    let layer_x = (x - layer.rect.x) as u32;
+                    let pixel = if layer.deep.is_empty() {
+                        widen(layer.data.get(layer_x, layer_y))
+                    } else {
+                        layer.deep.get(layer_x, layer_y)
+                    };
+                    let out = &mut pixels[(x - self.rect.x) as usize];
+                    *out = composite_rgba16(*out, pixel);
+                }
+            }
+        });
+        base
+    }
+
+    /// Saves with 16 bits per channel, flattened over `background`. Only
+    /// formats that support it, like PNG, will succeed.
+    pub fn save_16bit(&self, path: &Path, background: Rgba16) -> Result<(), ImageError> {
+        let image: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_raw(self.rect.w, self.rect.h, self.flatten_16bit(background).concat())
+            .expect("flattened data matches the image rect");
+        image.save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drawing_keeps_sixteen_bits() {
+        let mut image = Image::new(2, 1);
+        image.set_bit_depth(BitDepth::Sixteen);
+        let color = Color::new(0.5001, 0.25, 0.0, 1.0);
+        image.layers[0].draw_pixel(0, 0, color);
+        assert_eq!(image.layers[0].deep[0], [32774, 16384, 0, 65535]);
+        assert_eq!(image.layers[0].data[0], [128, 64, 0, 255]);
+        assert_eq!(image.layers[0].get_pixel(0, 0).map(to_rgba16), Some(image.layers[0].deep[0]));
+
+        image.set_bit_depth(BitDepth::Eight);
+        assert!(image.layers[0].deep.is_empty());
+    }
+
+    #[test]
+    fn setting_bytes_widens_them() {
+        let mut image = Image::new(2, 1);
+        image.set_bit_depth(BitDepth::Sixteen);
+        let layer = &mut image.layers[0];
+        layer.draw_pixel(0, 0, Color::new(0.5001, 0.5001, 0.5001, 1.0));
+        // Bytes the 16-bit value already rounds to leave it alone
+        layer.set_pixel(0, 0, [128, 128, 128, 255]);
+        layer.set_pixel(1, 0, [10, 20, 30, 255]);
+        assert_eq!(layer.deep.iter().copied().collect::<Vec<_>>(), vec![[32774, 32774, 32774, 65535], [2570, 5140, 7710, 65535]]);
+        assert_eq!(layer.data.get(1, 0), [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn duplicates_share_sixteen_bit_tiles() {
+        let mut image = Image::new(200, 200);
+        image.set_bit_depth(BitDepth::Sixteen);
+        image.layers[0].draw_pixel(0, 0, Color::new(0.5001, 0.25, 0.0, 1.0));
+        assert_eq!(image.layers[0].deep.allocated_tiles(), 1);
+        image.duplicate_layer(0);
+        image.layers[1].draw_pixel(199, 199, Color::new(0.0, 0.0, 1.0, 1.0));
+        assert_eq!(image.layers[0].deep.get(199, 199), [65535; 4]);
+        assert_eq!(image.layers[1].deep.get(0, 0), [32774, 16384, 0, 65535]);
+        assert_eq!(image.layers[1].deep.allocated_tiles(), 2);
+    }
+
+    #[test]
+    fn saves_and_opens_sixteen_bit_png() {
+        let mut image = Image::new(2, 2);
+        image.set_bit_depth(BitDepth::Sixteen);
+        image.layers[0].deep[0] = [1, 2, 3, 65535];
+        let path = std::env::temp_dir().join("pixel_editor_depth_test.png");
+        image.save(&path).unwrap();
+        let opened = Image::from_path(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(opened.is_16bit());
+        assert_eq!(opened.layers[0].deep[0], [1, 2, 3, 65535]);
+        assert_eq!(opened.layers[0].deep[3], [65535; 4]);
+    }
+}
@@ -0,0 +1,422 @@
+use rayon::prelude::*;
+use std::collections::VecDeque;
+
+use super::app::Color;
+use super::brush::Stamp;
+use super::layer::{self, Layer, ImageRect, Rgba8};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ColorDistance {
+    /// The largest difference between any two channels.
+    PerChannel,
+    /// A weighted euclidean distance that tracks perceived difference more
+    /// closely than raw channel values.
+    Perceptual,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FillOptions {
+    /// How far a color can be from the seed color and still be filled, from
+    /// 0.0 (exact match) to 1.0 (everything).
+    pub tolerance: f32,
+    pub distance: ColorDistance,
+    pub connectivity: Connectivity,
+    /// Only fill pixels connected to the seed. Otherwise every matching pixel
+    /// in the layer is filled.
+    pub contiguous: bool,
+    /// Find the region on the flattened image rather than the active layer.
+    pub sample_merged: bool,
+}
+
+impl Default for FillOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.0,
+            distance: ColorDistance::PerChannel,
+            connectivity: Connectivity::Four,
+            contiguous: true,
+            sample_merged: false,
+        }
+    }
+}
+
+/// Where a pattern fill places the top left corner of its first tile.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PatternAlign {
+    Canvas,
+    Click,
+}
+
+impl PatternAlign {
+    /// Where the first tile starts, in the coordinates of a layer placed at
+    /// `layer_rect`, for a fill clicked at (x, y) on it.
+    pub fn origin(self, layer_rect: ImageRect, x: i32, y: i32) -> (i32, i32) {
+        match self {
+            PatternAlign::Canvas => (-layer_rect.x, -layer_rect.y),
+            PatternAlign::Click => (x, y),
+        }
+    }
+}
+
+/// The color at (x, y) of `pattern` tiled out from `origin`. Tiles repeat in
+/// every direction, so pixels left of or above the origin wrap around too.
+pub fn pattern_color(pattern: &Stamp, origin: (i32, i32), x: i32, y: i32) -> Color {
+    pattern.tile(x - origin.0, y - origin.1)
+}
+
+/// Which pixels the Color Replace tool looks at.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ReplaceScope {
+    Layer,
+    /// The part of the active layer inside the selection.
+    Selection,
+    AllLayers,
+}
+
+/// The pixels selected by a fill, in the coordinates of the layer that was
+/// sampled.
+pub struct FillRegion {
+    pub w: u32,
+    pub h: u32,
+    pub mask: Vec<bool>,
+    /// The smallest rect containing every pixel of the region.
+    pub bounds: ImageRect,
+}
+
+impl FillRegion {
+    fn new(sample: &Layer, mask: Vec<bool>, min_x: i32, min_y: i32, max_x: i32, max_y: i32) -> Self {
+        Self {
+            w: sample.rect.w,
+            h: sample.rect.h,
+            mask,
+            bounds: ImageRect::new(min_x, min_y, (max_x - min_x + 1) as u32, (max_y - min_y + 1) as u32),
+        }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.w as i32 && y < self.h as i32 && self.mask[y as usize * self.w as usize + x as usize]
+    }
+}
+
+pub fn color_distance(a: Color, b: Color, distance: ColorDistance) -> f32 {
+    match distance {
+        ColorDistance::PerChannel => {
+            (a.r - b.r).abs().max((a.g - b.g).abs()).max((a.b - b.b).abs()).max((a.a - b.a).abs())
+        }
+        ColorDistance::Perceptual => {
+            // "Redmean" weighting, normalized so that black to white is 1.0
+            let mean_r = (a.r + b.r) / 2.0;
+            let dr = a.r - b.r;
+            let dg = a.g - b.g;
+            let db = a.b - b.b;
+            let da = a.a - b.a;
+            let d = (2.0 + mean_r) * dr * dr + 4.0 * dg * dg + (3.0 - mean_r) * db * db + 3.0 * da * da;
+            (d / 12.0).sqrt().min(1.0)
+        }
+    }
+}
+
+/// Sets every pixel within `tolerance` of `from` to `to`, only looking inside
+/// `clip` if one is given. Returns true if anything changed.
+pub fn replace_color(layer: &mut Layer, from: Color, to: Color, tolerance: f32, distance: ColorDistance, clip: Option<ImageRect>) -> bool {
+    let bounds = ImageRect::new(0, 0, layer.rect.w, layer.rect.h);
+    let area = match clip {
+        Some(clip) => clip.intersection(bounds),
+        None => bounds,
+    };
+    // Matching is the slow part, so rows are matched in parallel and only
+    // painted afterwards
+    let rows: Vec<Vec<i32>> = (area.y..area.y + area.h as i32).into_par_iter().map(|y| {
+        (area.x..area.x + area.w as i32).filter(|&x| {
+            let color = layer.get_pixel_unchecked(x, y);
+            color != to && (color == from || color_distance(color, from, distance) <= tolerance)
+        }).collect()
+    }).collect();
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (i32::MAX, i32::MAX, -1, -1);
+    for (y, xs) in (area.y..).zip(rows) {
+        for x in xs {
+            layer.draw_pixel_unchecked(x, y, to);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    if max_x < 0 {
+        return false;
+    }
+    layer.add_dirty_rect(ImageRect::new(min_x, min_y, (max_x - min_x + 1) as u32, (max_y - min_y + 1) as u32));
+    true
+}
+
+/// Finds the pixels of `sample` that a fill seeded at (x, y) would cover.
+/// Returns `None` if the seed is outside the layer.
+///
+/// Contiguous fills are found a horizontal span at a time, so each pixel is
+/// visited roughly once no matter how large the region is.
+pub fn find_region(sample: &Layer, x: i32, y: i32, options: &FillOptions) -> Option<FillRegion> {
+    let target_color = sample.get_pixel(x, y)?;
+    let target = layer::to_rgba8(target_color);
+    let (w, h) = (sample.rect.w as i32, sample.rect.h as i32);
+    let data = &sample.data;
+    let matches = |c: Rgba8| c == target || color_distance(layer::from_rgba8(c), target_color, options.distance) <= options.tolerance;
+
+    let mut mask = vec![false; data.len()];
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (x, y, x, y);
+
+    if !options.contiguous {
+        for py in 0..h {
+            for px in 0..w {
+                let i = (py * w + px) as usize;
+                if matches(data[i]) {
+                    mask[i] = true;
+                    min_x = min_x.min(px);
+                    min_y = min_y.min(py);
+                    max_x = max_x.max(px);
+                    max_y = max_y.max(py);
+                }
+            }
+        }
+        return Some(FillRegion::new(sample, mask, min_x, min_y, max_x, max_y));
+    }
+
+    // Spans on the rows above and below are scanned one pixel further out on
+    // each side to pick up diagonal neighbors.
+    let reach = match options.connectivity {
+        Connectivity::Four => 0,
+        Connectivity::Eight => 1,
+    };
+
+    let mut stack = vec![(x, y)];
+    while let Some((x, y)) = stack.pop() {
+        let row = (y * w) as usize;
+        if mask[row + x as usize] {
+            continue;
+        }
+
+        let mut left = x;
+        while left > 0 && !mask[row + left as usize - 1] && matches(data[row + left as usize - 1]) {
+            left -= 1;
+        }
+        let mut right = x;
+        while right < w - 1 && !mask[row + right as usize + 1] && matches(data[row + right as usize + 1]) {
+            right += 1;
+        }
+        mask[row + left as usize..=row + right as usize].iter_mut().for_each(|m| *m = true);
+        min_x = min_x.min(left);
+        max_x = max_x.max(right);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+
+        for ny in [y - 1, y + 1] {
+            if ny < 0 || ny >= h {
+                continue;
+            }
+            let row = (ny * w) as usize;
+            let mut in_span = false;
+            for nx in (left - reach).max(0)..=(right + reach).min(w - 1) {
+                let i = row + nx as usize;
+                let fillable = !mask[i] && matches(data[i]);
+                if fillable && !in_span {
+                    stack.push((nx, ny));
+                }
+                in_span = fillable;
+            }
+        }
+    }
+
+    Some(FillRegion::new(sample, mask, min_x, min_y, max_x, max_y))
+}
+
+/// A pixel-at-a-time breadth-first version of `find_region`. It's much slower
+/// on large regions, and is kept to check and benchmark the scanline fill
+/// against.
+pub fn find_region_queue(sample: &Layer, x: i32, y: i32, options: &FillOptions) -> Option<FillRegion> {
+    if !options.contiguous {
+        return find_region(sample, x, y, options);
+    }
+    let target_color = sample.get_pixel(x, y)?;
+    let w = sample.rect.w;
+    let matches = |c: Color| c == target_color || color_distance(c, target_color, options.distance) <= options.tolerance;
+
+    let mut mask = vec![false; sample.data.len()];
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (x, y, x, y);
+
+    let neighbors: &[(i32, i32)] = match options.connectivity {
+        Connectivity::Four => &[(-1, 0), (1, 0), (0, -1), (0, 1)],
+        Connectivity::Eight => &[(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (1, -1), (-1, 1), (1, 1)],
+    };
+    mask[y as usize * w as usize + x as usize] = true;
+    let mut queue = VecDeque::new();
+    queue.push_back((x, y));
+    while let Some((x, y)) = queue.pop_front() {
+        for (dx, dy) in neighbors {
+            let (nx, ny) = (x + dx, y + dy);
+            if let Some(color) = sample.get_pixel(nx, ny) {
+                let i = ny as usize * w as usize + nx as usize;
+                if !mask[i] && matches(color) {
+                    mask[i] = true;
+                    queue.push_back((nx, ny));
+                    min_x = min_x.min(nx);
+                    min_y = min_y.min(ny);
+                    max_x = max_x.max(nx);
+                    max_y = max_y.max(ny);
+                }
+            }
+        }
+    }
+
+    Some(FillRegion::new(sample, mask, min_x, min_y, max_x, max_y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// A layer of random blobs in a few colors, so fills find irregular
+    /// regions with holes and diagonal-only connections.
+    fn noise_layer(seed: u64, w: u32, h: u32) -> Layer {
+        let colors = [app::WHITE, app::BLACK, Color::new(0.9, 0.9, 0.9, 1.0)];
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut layer = Layer::new(ImageRect::new(0, 0, w, h));
+        for y in 0..h {
+            for x in 0..w {
+                layer.data.set(x, y, layer::to_rgba8(colors[rng.gen_range(0, colors.len())]));
+            }
+        }
+        layer
+    }
+
+    #[test]
+    fn scanline_matches_queue_fill() {
+        let base = FillOptions::default();
+        let options = [
+            base,
+            FillOptions { connectivity: Connectivity::Eight, ..base },
+            FillOptions { tolerance: 0.2, ..base },
+            FillOptions { tolerance: 0.2, connectivity: Connectivity::Eight, distance: ColorDistance::Perceptual, ..base },
+        ];
+        for seed in 0..20 {
+            let layer = noise_layer(seed, 37, 23);
+            for options in &options {
+                for &(x, y) in &[(0, 0), (36, 22), (18, 11), (5, 17)] {
+                    let scanline = find_region(&layer, x, y, options).unwrap();
+                    let queue = find_region_queue(&layer, x, y, options).unwrap();
+                    assert!(scanline.mask == queue.mask, "seed {} at ({}, {}) with {:?}", seed, x, y, options);
+                    let (a, b) = (scanline.bounds, queue.bounds);
+                    assert_eq!((a.x, a.y, a.w, a.h), (b.x, b.y, b.w, b.h));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn eight_connectivity_crosses_diagonals() {
+        // A diagonal line of black pixels splitting the layer into two halves
+        let mut layer = Layer::new(ImageRect::new(0, 0, 8, 8));
+        for i in 0..8 {
+            layer.draw_pixel(i, i, app::BLACK);
+        }
+        let four = FillOptions::default();
+        let eight = FillOptions { connectivity: Connectivity::Eight, ..four };
+
+        let region = find_region(&layer, 7, 0, &four).unwrap();
+        assert!(!region.contains(0, 7));
+        assert_eq!(region.mask.iter().filter(|&&m| m).count(), 28);
+
+        let region = find_region(&layer, 7, 0, &eight).unwrap();
+        assert!(region.contains(0, 7));
+    }
+
+    #[test]
+    fn replace_color_respects_tolerance_and_clip() {
+        let mut layer = Layer::new(ImageRect::new(0, 0, 4, 4));
+        layer.draw_pixel(0, 0, Color::new(0.9, 0.9, 0.9, 1.0));
+        layer.clear_dirty_rect();
+        assert!(replace_color(&mut layer, app::WHITE, app::BLACK, 0.0, ColorDistance::PerChannel, Some(ImageRect::new(2, 2, 8, 8))));
+        assert_eq!(layer.get_pixel(1, 1), Some(app::WHITE));
+        assert_eq!(layer.get_pixel(3, 3), Some(app::BLACK));
+        let dirty = layer.dirty_rect;
+        assert_eq!((dirty.x, dirty.y, dirty.w, dirty.h), (2, 2, 2, 2));
+
+        replace_color(&mut layer, app::WHITE, app::BLACK, 0.15, ColorDistance::PerChannel, None);
+        assert!(layer.data.iter().all(|&c| c == layer::to_rgba8(app::BLACK)));
+    }
+
+    #[test]
+    fn fill_marks_only_filled_bounds_dirty() {
+        let mut layer = Layer::new(ImageRect::new(0, 0, 8, 8));
+        layer.fill_with(0, 0, app::BLACK, &FillOptions { contiguous: false, ..FillOptions::default() }, None);
+        layer.clear_dirty_rect();
+        layer.draw_line(2, 3, 4, 3, app::WHITE);
+        layer.clear_dirty_rect();
+        layer.fill(3, 3, app::RED);
+        let dirty = layer.dirty_rect;
+        assert_eq!((dirty.x, dirty.y, dirty.w, dirty.h), (2, 3, 3, 1));
+    }
+
+    /// A 3x2 pattern whose pixels are numbered 0 to 5 in their red channel.
+    fn numbered_pattern() -> Stamp {
+        Stamp {
+            w: 3,
+            h: 2,
+            data: (0..6).map(|i| Color::from_rgba(i, 0, 0, 255)).collect(),
+        }
+    }
+
+    /// The pattern numbers along row `y` of `layer`.
+    fn pattern_row(layer: &Layer, y: u32) -> Vec<u8> {
+        (0..layer.rect.w).map(|x| layer.data.get(x, y)[0]).collect()
+    }
+
+    #[test]
+    fn pattern_origin_follows_alignment() {
+        let layer_rect = ImageRect::new(4, -3, 10, 10);
+        assert_eq!(PatternAlign::Canvas.origin(layer_rect, 7, 2), (-4, 3));
+        assert_eq!(PatternAlign::Click.origin(layer_rect, 7, 2), (7, 2));
+
+        let pattern = numbered_pattern();
+        let number = |origin, x, y| layer::to_rgba8(pattern_color(&pattern, origin, x, y))[0];
+        assert_eq!(number((0, 0), 0, 0), 0);
+        assert_eq!(number((0, 0), 4, 1), 4);
+        // Left of and above the origin wraps around to the far side
+        assert_eq!(number((0, 0), -1, 0), 2);
+        assert_eq!(number((0, 0), -1, -1), 5);
+        assert_eq!(number((5, 5), 5, 5), 0);
+        assert_eq!(number((5, 5), 4, 3), 2);
+        assert_eq!(number((-4, 3), -7, 2), 3);
+    }
+
+    #[test]
+    fn pattern_fill_aligns_to_canvas_or_click() {
+        let pattern = numbered_pattern();
+        let options = FillOptions::default();
+
+        // A layer placed one pixel right of the canvas origin starts one
+        // pixel into the pattern
+        let mut canvas = Layer::new(ImageRect::new(1, 0, 5, 2));
+        canvas.fill_pattern(3, 1, &pattern, PatternAlign::Canvas, &options, None);
+        assert_eq!(pattern_row(&canvas, 0), vec![1, 2, 0, 1, 2]);
+        assert_eq!(pattern_row(&canvas, 1), vec![4, 5, 3, 4, 5]);
+
+        let mut click = Layer::new(ImageRect::new(1, 0, 5, 2));
+        click.fill_pattern(3, 1, &pattern, PatternAlign::Click, &options, None);
+        assert_eq!(pattern_row(&click, 0), vec![3, 4, 5, 3, 4]);
+        assert_eq!(pattern_row(&click, 1), vec![0, 1, 2, 0, 1]);
+
+        // Only the region around the click is painted
+        let mut walled = Layer::new(ImageRect::new(0, 0, 5, 2));
+        walled.draw_line(2, 0, 2, 1, app::BLACK);
+        walled.fill_pattern(4, 0, &pattern, PatternAlign::Click, &options, None);
+        assert_eq!(pattern_row(&walled, 0), vec![255, 255, 0, 2, 0]);
+    }
+}
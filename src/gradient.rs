@@ -0,0 +1,197 @@
+use super::app::Color;
+use super::layer::{Layer, ImageRect};
+use super::util::lerp_color;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GradientShape {
+    Linear,
+    Radial,
+    Angular,
+    Diamond,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Gradient {
+    pub shape: GradientShape,
+    /// Render with ordered dithering, using only the stop colors themselves
+    /// instead of blending between them.
+    pub dither: bool,
+}
+
+/// Returns the 8x8 Bayer matrix threshold for (x, y), in [0, 1).
+pub fn bayer_threshold(x: i32, y: i32) -> f32 {
+    let (x, y) = (x.rem_euclid(8) as u32, y.rem_euclid(8) as u32);
+    let mut value = 0;
+    // Interleave the bits of x ^ y and y, with the lowest bits of the
+    // coordinates becoming the highest bits of the value
+    for bit in 0..3 {
+        let mask = 1 << bit;
+        value = (value << 2) | ((((x ^ y) & mask) != 0) as u32) << 1 | ((y & mask) != 0) as u32;
+    }
+    value as f32 / 64.0
+}
+
+impl Gradient {
+    pub fn new() -> Self {
+        Self {
+            shape: GradientShape::Linear,
+            dither: false,
+        }
+    }
+
+    /// Position along the gradient of (x, y), from 0.0 at `start` to 1.0 at
+    /// `end`.
+    pub fn position(&self, start: (i32, i32), end: (i32, i32), x: i32, y: i32) -> f32 {
+        let (dx, dy) = ((end.0 - start.0) as f32, (end.1 - start.1) as f32);
+        let (px, py) = ((x - start.0) as f32, (y - start.1) as f32);
+        let length_sq = dx * dx + dy * dy;
+        if length_sq == 0.0 {
+            return 0.0;
+        }
+        let length = length_sq.sqrt();
+        let t = match self.shape {
+            GradientShape::Linear => (px * dx + py * dy) / length_sq,
+            GradientShape::Radial => (px * px + py * py).sqrt() / length,
+            GradientShape::Angular => {
+                let angle = py.atan2(px) - dy.atan2(dx);
+                angle.rem_euclid(std::f32::consts::TAU) / std::f32::consts::TAU
+            }
+            GradientShape::Diamond => {
+                let along = (px * dx + py * dy) / length;
+                let across = (px * dy - py * dx) / length;
+                (along.abs() + across.abs()) / length
+            }
+        };
+        t.clamp(0.0, 1.0)
+    }
+
+    /// The color at position `t` across `stops`, dithered by `threshold` when
+    /// dithering is on.
+    pub fn color_at(&self, stops: &[Color], t: f32, threshold: f32) -> Color {
+        if stops.len() == 1 {
+            return stops[0];
+        }
+        let s = t * (stops.len() - 1) as f32;
+        let i = (s.floor() as usize).min(stops.len() - 2);
+        let f = s - i as f32;
+        if self.dither {
+            if f > threshold {
+                stops[i + 1]
+            } else {
+                stops[i]
+            }
+        } else {
+            lerp_color(stops[i], stops[i + 1], f)
+        }
+    }
+
+    /// Fills the layer with the gradient from `start` to `end` across `stops`,
+    /// only touching pixels inside `clip` if one is given. All coordinates are
+    /// in the layer's space.
+    pub fn draw(&self, layer: &mut Layer, start: (i32, i32), end: (i32, i32), stops: &[Color], clip: Option<ImageRect>) {
+        if stops.is_empty() {
+            return;
+        }
+        let bounds = ImageRect::new(0, 0, layer.rect.w, layer.rect.h);
+        let area = match clip {
+            Some(clip) => clip.intersection(bounds),
+            None => bounds,
+        };
+        for y in area.y..area.y + area.h as i32 {
+            for x in area.x..area.x + area.w as i32 {
+                let t = self.position(start, end, x, y);
+                layer.draw_pixel(x, y, self.color_at(stops, t, bayer_threshold(x, y)));
+            }
+        }
+        layer.add_dirty_rect(area);
+    }
+}
+
+impl Default for Gradient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_matrix_uses_every_level_once() {
+        let mut levels: Vec<u32> = (0..8).flat_map(|y| (0..8).map(move |x| (bayer_threshold(x, y) * 64.0) as u32)).collect();
+        levels.sort();
+        assert_eq!(levels, (0..64).collect::<Vec<u32>>());
+        assert_eq!(bayer_threshold(1, 0), 0.5);
+        assert_eq!(bayer_threshold(-8, 16), 0.0);
+    }
+
+    fn gradient(shape: GradientShape) -> Gradient {
+        Gradient { shape, dither: false }
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    fn assert_color_close(a: Color, b: Color) {
+        for (a, b) in [(a.r, b.r), (a.g, b.g), (a.b, b.b), (a.a, b.a)] {
+            assert_close(a, b);
+        }
+    }
+
+    #[test]
+    fn positions_at_known_points() {
+        let (start, end) = ((0, 0), (10, 0));
+        let at = |shape, x, y| gradient(shape).position(start, end, x, y);
+
+        assert_close(at(GradientShape::Linear, 5, 0), 0.5);
+        assert_close(at(GradientShape::Linear, 5, 7), 0.5);
+        assert_close(at(GradientShape::Linear, -3, 0), 0.0);
+        assert_close(at(GradientShape::Linear, 20, 0), 1.0);
+
+        assert_close(at(GradientShape::Radial, 0, 5), 0.5);
+        assert_close(at(GradientShape::Radial, -3, 4), 0.5);
+        assert_close(at(GradientShape::Radial, 6, 8), 1.0);
+
+        // Angles run from the drag direction, clockwise on screen
+        assert_close(at(GradientShape::Angular, 10, 0), 0.0);
+        assert_close(at(GradientShape::Angular, 0, 10), 0.25);
+        assert_close(at(GradientShape::Angular, -10, 0), 0.5);
+        assert_close(at(GradientShape::Angular, 0, -10), 0.75);
+
+        assert_close(at(GradientShape::Diamond, 5, 0), 0.5);
+        assert_close(at(GradientShape::Diamond, 0, -5), 0.5);
+        assert_close(at(GradientShape::Diamond, 3, 2), 0.5);
+        assert_close(at(GradientShape::Diamond, 10, 10), 1.0);
+    }
+
+    #[test]
+    fn zero_length_drag_is_all_start() {
+        for shape in [GradientShape::Linear, GradientShape::Radial, GradientShape::Angular, GradientShape::Diamond] {
+            for (x, y) in [(4, 4), (0, 0), (-7, 3)] {
+                assert_eq!(gradient(shape).position((4, 4), (4, 4), x, y), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn colors_interpolate_between_stops() {
+        let (red, green, blue) = (Color::new(1.0, 0.0, 0.0, 1.0), Color::new(0.0, 1.0, 0.0, 1.0), Color::new(0.0, 0.0, 1.0, 1.0));
+        let stops = [red, green, blue];
+        let smooth = gradient(GradientShape::Linear);
+        assert_color_close(smooth.color_at(&stops, 0.0, 0.0), red);
+        assert_color_close(smooth.color_at(&stops, 1.0, 0.0), blue);
+        assert_color_close(smooth.color_at(&stops, 0.5, 0.0), green);
+        assert_color_close(smooth.color_at(&stops, 0.25, 0.0), Color::new(0.5, 0.5, 0.0, 1.0));
+        assert_color_close(smooth.color_at(&[green], 0.7, 0.0), green);
+
+        // Dithering picks one of the two stops around t by the threshold, and
+        // the endpoints stay on their stops at any threshold
+        let dithered = Gradient { dither: true, ..smooth };
+        assert_color_close(dithered.color_at(&stops, 0.25, 0.25), green);
+        assert_color_close(dithered.color_at(&stops, 0.25, 0.75), red);
+        assert_color_close(dithered.color_at(&stops, 0.0, 0.0), red);
+        assert_color_close(dithered.color_at(&stops, 1.0, 63.0 / 64.0), blue);
+    }
+}
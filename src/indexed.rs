@@ -0,0 +1,379 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::app::Color;
+use super::depth::BitDepth;
+use super::layer::{Image, Layer, ImageRect};
+use super::palette::Palette;
+use super::quantize::{self, Dither, palette_rgb};
+use super::tiles::TiledPixels;
+use super::util::color_to_rgba8;
+
+/// The most colors an indexed image can have, since indices are bytes.
+pub const MAX_INDEXED_COLORS: usize = 256;
+
+#[derive(Debug)]
+pub enum IndexedError {
+    /// The image isn't in indexed mode.
+    NotIndexed,
+    /// The palette has more colors than indices can address, or no room left
+    /// for a transparent index when one is needed.
+    TooManyColors(usize),
+    EmptyPalette,
+    /// The file extension isn't .png or .gif.
+    UnknownFormat,
+    /// The image is wider or taller than a GIF can be.
+    TooLarge(u32, u32),
+    Io(io::Error),
+    Png(png::EncodingError),
+    Gif(gif::EncodingError),
+}
+
+impl fmt::Display for IndexedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexedError::NotIndexed => write!(f, "the image isn't in indexed mode"),
+            IndexedError::TooManyColors(n) => write!(f, "{} colors is more than an indexed image can hold", n),
+            IndexedError::EmptyPalette => write!(f, "the palette is empty"),
+            IndexedError::UnknownFormat => write!(f, "indexed images can only be exported as .png or .gif"),
+            IndexedError::TooLarge(w, h) => write!(f, "{}x{} is too large for a GIF, which can be at most {}x{}", w, h, u16::MAX, u16::MAX),
+            IndexedError::Io(e) => write!(f, "{}", e),
+            IndexedError::Png(e) => write!(f, "{}", e),
+            IndexedError::Gif(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for IndexedError {
+    fn from(e: io::Error) -> Self {
+        IndexedError::Io(e)
+    }
+}
+
+impl From<png::EncodingError> for IndexedError {
+    fn from(e: png::EncodingError) -> Self {
+        IndexedError::Png(e)
+    }
+}
+
+impl From<gif::EncodingError> for IndexedError {
+    fn from(e: gif::EncodingError) -> Self {
+        IndexedError::Gif(e)
+    }
+}
+
+/// A flattened indexed image, ready to encode.
+pub struct IndexedData {
+    pub pixels: Vec<u8>,
+    /// RGB triples.
+    pub palette: Vec<u8>,
+    /// The index of the entry added for transparent pixels, if there are any.
+    pub transparent: Option<u8>,
+}
+
+/// Puts `layer` in indexed mode, pointing every pixel at its nearest color in
+/// `palette` and repainting it with that color. Pixels less than half opaque
+/// become transparent. From then on, writes to the layer snap to the palette
+/// as they're made.
+pub fn index_layer(layer: &mut Layer, palette: &Palette) {
+    layer.palette = Arc::from(palette_rgb(palette));
+    layer.indices = TiledPixels::new(layer.rect.w, layer.rect.h, None);
+    for y in 0..layer.rect.h {
+        for x in 0..layer.rect.w {
+            layer.set_pixel(x, y, layer.data.get(x, y));
+        }
+    }
+}
+
+impl Image {
+    pub fn is_indexed(&self) -> bool {
+        self.palette.is_some()
+    }
+
+    /// Switches to indexed mode, remapping every layer to `palette`.
+    pub fn convert_to_indexed(&mut self, palette: Palette, dither: Dither) -> Result<(), IndexedError> {
+        if palette.colors.is_empty() {
+            return Err(IndexedError::EmptyPalette);
+        }
+        if palette.colors.len() > MAX_INDEXED_COLORS {
+            return Err(IndexedError::TooManyColors(palette.colors.len()));
+        }
+        let palette = Palette {
+            colors: palette.colors.iter().map(|c| Color::new(c.r, c.g, c.b, 1.0)).collect(),
+            ..palette
+        };
+        self.set_bit_depth(BitDepth::Eight);
+        for layer in &mut self.layers {
+            quantize::remap_layer(layer, &palette, dither);
+            index_layer(layer, &palette);
+        }
+        self.palette = Some(palette);
+        Ok(())
+    }
+
+    /// Switches back to RGBA mode. The pixels keep their palette colors.
+    pub fn convert_to_rgba(&mut self) {
+        self.palette = None;
+        for layer in &mut self.layers {
+            layer.indices = TiledPixels::default();
+            layer.palette = Arc::default();
+        }
+    }
+
+    /// Hands every layer the current palette, so what's drawn next snaps to
+    /// it. Runs after each palette edit.
+    fn share_palette(&mut self) {
+        let Some(palette) = &self.palette else {
+            return;
+        };
+        let colors: Arc<[[u8; 3]]> = Arc::from(palette_rgb(palette));
+        for layer in &mut self.layers {
+            layer.palette = colors.clone();
+        }
+    }
+
+    /// Changes a palette entry, recoloring every pixel that uses it.
+    pub fn set_palette_color(&mut self, index: usize, color: Color) {
+        let Some(palette) = &mut self.palette else {
+            return;
+        };
+        let color = Color::new(color.r, color.g, color.b, 1.0);
+        palette.set(index, color);
+        self.share_palette();
+        self.update_pixels(|i| i.map(usize::from).filter(|&i| i == index));
+    }
+
+    /// Adds a color to the end of the palette. Returns false if the palette
+    /// is full.
+    pub fn add_palette_color(&mut self, color: Color) -> bool {
+        match &mut self.palette {
+            Some(palette) if palette.colors.len() < MAX_INDEXED_COLORS => {
+                palette.add(Color::new(color.r, color.g, color.b, 1.0));
+                self.share_palette();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes a palette entry. Pixels using it move to the nearest remaining
+    /// color. The last color can't be removed.
+    pub fn remove_palette_color(&mut self, index: usize) {
+        let Some(palette) = &mut self.palette else {
+            return;
+        };
+        if palette.colors.len() < 2 || index >= palette.colors.len() {
+            return;
+        }
+        let removed = color_to_rgba8(palette.colors[index]);
+        palette.remove(index);
+        let nearest = quantize::nearest_color(&palette_rgb(palette), [removed[0], removed[1], removed[2]]);
+        self.share_palette();
+        self.update_pixels(|i| i.map(|i| match (i as usize).cmp(&index) {
+            std::cmp::Ordering::Less => i as usize,
+            std::cmp::Ordering::Equal => nearest,
+            std::cmp::Ordering::Greater => i as usize - 1,
+        }));
+    }
+
+    /// Moves a palette entry from `from` to `to`, keeping every pixel's color.
+    pub fn move_palette_color(&mut self, from: usize, to: usize) {
+        let Some(palette) = &mut self.palette else {
+            return;
+        };
+        let len = palette.colors.len();
+        if from >= len || to >= len {
+            return;
+        }
+        palette.move_color(from, to);
+        let mut order: Vec<usize> = (0..len).collect();
+        let moved = order.remove(from);
+        order.insert(to, moved);
+        // order[new] = old, so invert it to find where each old index went
+        let mut new_index = vec![0; len];
+        for (new, old) in order.into_iter().enumerate() {
+            new_index[old] = new;
+        }
+        self.share_palette();
+        self.update_pixels(|i| i.map(|i| new_index[i as usize]));
+    }
+
+    /// Rewrites every index with `remap`, where `None` leaves the pixel alone,
+    /// and repaints the pixels that changed.
+    fn update_pixels<F: Fn(Option<u8>) -> Option<usize>>(&mut self, remap: F) {
+        for layer in &mut self.layers {
+            let (mut min_x, mut min_y, mut max_x, mut max_y) = (i32::MAX, i32::MAX, -1, -1);
+            let w = layer.rect.w as usize;
+            for i in 0..layer.indices.len() {
+                let Some(new) = remap(layer.indices[i]) else {
+                    continue;
+                };
+                let (x, y) = ((i % w) as i32, (i / w) as i32);
+                layer.set_index(x as u32, y as u32, Some(new as u8));
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+            if max_x >= 0 {
+                layer.add_dirty_rect(ImageRect::new(min_x, min_y, (max_x - min_x + 1) as u32, (max_y - min_y + 1) as u32));
+            }
+        }
+    }
+
+    /// The flattened image as palette indices. If any pixel is transparent,
+    /// an extra palette entry is added for it.
+    pub fn indexed_data(&self) -> Result<IndexedData, IndexedError> {
+        let palette = self.palette.as_ref().ok_or(IndexedError::NotIndexed)?;
+        let targets = palette_rgb(palette);
+        let flat = self.flatten();
+        let transparent = if flat.data.iter().any(|p| p[3] < 128) {
+            if targets.len() >= MAX_INDEXED_COLORS {
+                return Err(IndexedError::TooManyColors(targets.len() + 1));
+            }
+            Some(targets.len() as u8)
+        } else {
+            None
+        };
+        let pixels = flat.data.iter().map(|c| {
+            let [r, g, b, a] = *c;
+            match transparent {
+                Some(t) if a < 128 => t,
+                _ => quantize::nearest_color(&targets, [r, g, b]) as u8,
+            }
+        }).collect();
+        let mut rgb: Vec<u8> = targets.concat();
+        if transparent.is_some() {
+            rgb.extend_from_slice(&[0, 0, 0]);
+        }
+        Ok(IndexedData { pixels, palette: rgb, transparent })
+    }
+
+    /// Saves a paletted PNG or GIF, picking the format from the extension.
+    pub fn save_indexed(&self, path: &Path) -> Result<(), IndexedError> {
+        let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        let (w, h) = (self.rect.w, self.rect.h);
+        if extension.as_deref() == Some("gif") && (w > u16::MAX as u32 || h > u16::MAX as u32) {
+            return Err(IndexedError::TooLarge(w, h));
+        }
+        let IndexedData { pixels, palette: rgb, transparent } = self.indexed_data()?;
+        let file = BufWriter::new(File::create(path)?);
+        match extension.as_deref() {
+            Some("png") => {
+                let mut encoder = png::Encoder::new(file, w, h);
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_palette(rgb);
+                if let Some(t) = transparent {
+                    // Alpha for every entry up to the transparent one
+                    let mut trns = vec![255; t as usize];
+                    trns.push(0);
+                    encoder.set_trns(trns);
+                }
+                encoder.write_header()?.write_image_data(&pixels)?;
+            }
+            Some("gif") => {
+                let mut encoder = gif::Encoder::new(file, w as u16, h as u16, &rgb)?;
+                let frame = gif::Frame::from_indexed_pixels(w as u16, h as u16, &pixels, transparent);
+                encoder.write_frame(&frame)?;
+            }
+            _ => return Err(IndexedError::UnknownFormat),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app;
+    use super::super::layer::TRANSPARENT;
+
+    fn two_color_image() -> Image {
+        let mut image = Image::new(4, 4);
+        image.layers[0].draw_line(0, 0, 3, 0, app::RED);
+        let mut palette = Palette::new("Test");
+        palette.add(Color::from_rgba(255, 255, 255, 255));
+        palette.add(Color::from_rgba(200, 0, 0, 255));
+        image.convert_to_indexed(palette, Dither::None).unwrap();
+        image
+    }
+
+    #[test]
+    fn editing_an_entry_recolors_its_pixels() {
+        let mut image = two_color_image();
+        assert_eq!(image.layers[0].indices[0], Some(1));
+        assert_eq!(image.layers[0].data[0], [200, 0, 0, 255]);
+
+        let blue = Color::from_rgba(0, 0, 255, 255);
+        image.set_palette_color(1, blue);
+        assert_eq!(image.layers[0].get_pixel(3, 0), Some(blue));
+        assert_eq!(image.layers[0].get_pixel(0, 1), Some(app::WHITE));
+
+        image.move_palette_color(1, 0);
+        assert_eq!(image.layers[0].indices[0], Some(0));
+        assert_eq!(image.layers[0].get_pixel(0, 0), Some(blue));
+
+        image.remove_palette_color(0);
+        assert!(image.layers[0].indices.iter().all(|&i| i == Some(0)));
+    }
+
+    #[test]
+    fn drawing_snaps_to_the_palette() {
+        let mut image = two_color_image();
+        image.layers[0].draw_pixel(2, 2, Color::from_rgba(250, 20, 20, 255));
+        assert_eq!(image.layers[0].indices[10], Some(1));
+        assert_eq!(image.layers[0].data[10], [200, 0, 0, 255]);
+    }
+
+    #[test]
+    fn added_colors_can_be_drawn_on_duplicated_layers() {
+        let mut image = two_color_image();
+        image.duplicate_layer(0);
+        let blue = Color::from_rgba(0, 0, 255, 255);
+        assert!(image.add_palette_color(blue));
+        image.layers[1].draw_pixel(1, 1, Color::from_rgba(10, 10, 240, 255));
+        assert_eq!(image.layers[1].indices[5], Some(2));
+        assert_eq!(image.layers[1].get_pixel(1, 1), Some(blue));
+        assert_eq!(image.layers[0].indices[5], Some(0));
+    }
+
+    #[test]
+    fn exports_transparency_as_an_extra_index() {
+        let mut image = two_color_image();
+        image.layers[0].set_pixel(3, 3, TRANSPARENT);
+        assert_eq!(image.layers[0].indices[15], None);
+        let data = image.indexed_data().unwrap();
+        assert_eq!(data.transparent, Some(2));
+        assert_eq!(data.palette.len(), 9);
+        assert_eq!(data.pixels[15], 2);
+        assert_eq!(&data.pixels[..5], &[1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn exported_files_decode_to_the_same_colors() {
+        let image = two_color_image();
+        for extension in ["png", "gif"] {
+            let path = std::env::temp_dir().join(format!("pixel_editor_indexed_test.{}", extension));
+            image.save_indexed(&path).unwrap();
+            let decoded = image::open(&path).unwrap().to_rgba8();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(decoded.get_pixel(0, 0).0, [200, 0, 0, 255], "{}", extension);
+            assert_eq!(decoded.get_pixel(3, 3).0, [255, 255, 255, 255], "{}", extension);
+        }
+    }
+
+    #[test]
+    fn refuses_gifs_too_large_to_encode() {
+        let mut image = Image::new(70000, 1);
+        let mut palette = Palette::new("Test");
+        palette.add(Color::from_rgba(255, 255, 255, 255));
+        image.convert_to_indexed(palette, Dither::None).unwrap();
+        let path = std::env::temp_dir().join("pixel_editor_indexed_too_large.gif");
+        assert!(matches!(image.save_indexed(&path), Err(IndexedError::TooLarge(70000, 1))));
+        assert!(!path.exists());
+    }
+}
@@ -1,14 +1,21 @@
 #![allow(dead_code)]
 
-use image::RgbaImage;
+use image::{DynamicImage, RgbaImage};
 use image::error::ImageError;
 use std::path::Path;
-use std::collections::VecDeque;
 use std::cmp::{min, max};
+use std::sync::Arc;
 
 use super::app::{self, Color};
-
-#[derive(Copy, Clone, Debug)]
+use super::depth::{self, BitDepth, Rgba16};
+use super::brush::Stamp;
+use super::fill::{self, FillOptions, FillRegion, PatternAlign};
+use super::palette::Palette;
+use super::quantize;
+use super::tiles::TiledPixels;
+use super::util;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct ImageRect {
     pub x: i32,
     pub y: i32,
@@ -26,47 +33,178 @@ impl ImageRect {
         }
     }
 
+    /// Whether the rect covers no pixels. Empty rects are ignored by
+    /// `union` and never intersect anything, wherever they're positioned.
+    pub fn is_empty(&self) -> bool {
+        self.w == 0 || self.h == 0
+    }
+
+    /// The x just past the right edge.
+    pub fn right(&self) -> i32 {
+        self.x + self.w as i32
+    }
+
+    /// The y just past the bottom edge.
+    pub fn bottom(&self) -> i32 {
+        self.y + self.h as i32
+    }
+
+    /// The smallest rect covering both rects.
     pub fn union(&self, other: ImageRect) -> ImageRect {
-        if self.w == 0 || self.h == 0 {
+        if self.is_empty() {
             return other;
         }
-        if other.w == 0 || other.h == 0 {
+        if other.is_empty() {
             return *self;
         }
-        let r = ImageRect {
-            x: min(self.x, other.x),
-            y: min(self.y, other.y),
-            w: (max(self.x + self.w as i32, other.x + other.w as i32) - min(self.x, other.x)) as u32,
-            h: (max(self.y + self.h as i32, other.y + other.h as i32) - min(self.y, other.y)) as u32,
-        };
-        return r;
+        let (x, y) = (min(self.x, other.x), min(self.y, other.y));
+        ImageRect {
+            x,
+            y,
+            w: (max(self.right(), other.right()) - x) as u32,
+            h: (max(self.bottom(), other.bottom()) - y) as u32,
+        }
     }
 
+    /// Whether the rects share at least one pixel. Rects that only touch
+    /// along an edge or at a corner don't.
     pub fn has_intersection(&self, other: ImageRect) -> bool {
-        self.x <= other.x + other.w as i32 && self.x + self.w as i32 >= other.x
-         && self.y <= other.y + other.h as i32 && self.y + self.h as i32 >= other.y
+        !self.is_empty() && !other.is_empty()
+            && self.x < other.right() && other.x < self.right()
+            && self.y < other.bottom() && other.y < self.bottom()
     }
 
+    /// The pixels both rects cover, or an empty rect at the origin if there
+    /// are none.
     pub fn intersection(&self, other: ImageRect) -> ImageRect {
-        if !ImageRect::has_intersection(self, other) {
+        if !self.has_intersection(other) {
             return ImageRect::new(0, 0, 0, 0);
         }
+        let (x, y) = (max(self.x, other.x), max(self.y, other.y));
         ImageRect {
-            x: max(self.x, other.x),
-            y: max(self.y, other.y),
-            w: (min(self.x + self.w as i32, other.x + other.w as i32) - max(self.x, other.x)) as u32,
-            h: (min(self.y + self.h as i32, other.y + other.h as i32) - max(self.y, other.y)) as u32,
+            x,
+            y,
+            w: (min(self.right(), other.right()) - x) as u32,
+            h: (min(self.bottom(), other.bottom()) - y) as u32,
         }
     }
 
     pub fn contains(&self, x: i32, y: i32) -> bool {
-        x >= self.x && x < self.x + self.w as i32 && y >= self.y && y < self.y + self.h as i32
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+}
+
+/// Returns the pixels of the line from (x1, y1) to (x2, y2) using integer
+/// Bresenham rasterization, in order from the first point to the second.
+///
+/// The line is always traced from the same canonical endpoint so that A→B and
+/// B→A cover exactly the same pixels.
+pub fn line_points(x1: i32, y1: i32, x2: i32, y2: i32) -> Vec<(i32, i32)> {
+    let reversed = (x2, y2) < (x1, y1);
+    let (x1, y1, x2, y2) = if reversed { (x2, y2, x1, y1) } else { (x1, y1, x2, y2) };
+
+    let dx = (x2 - x1).abs();
+    let dy = -(y2 - y1).abs();
+    let sx = if x1 < x2 { 1 } else { -1 };
+    let sy = if y1 < y2 { 1 } else { -1 };
+    let mut err = dx + dy;
+    let (mut x, mut y) = (x1, y1);
+
+    let mut points = Vec::with_capacity(max(dx, -dy) as usize + 1);
+    loop {
+        points.push((x, y));
+        if x == x2 && y == y2 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+
+    if reversed {
+        points.reverse();
+    }
+    points
+}
+
+/// A pixel as layers store it: red, green, blue and alpha bytes. Tools work
+/// in `Color` and convert at `get_pixel` and `draw_pixel`.
+pub type Rgba8 = [u8; 4];
+
+pub const TRANSPARENT: Rgba8 = [0, 0, 0, 0];
+
+pub fn to_rgba8(color: Color) -> Rgba8 {
+    util::color_to_rgba8(color)
+}
+
+pub fn from_rgba8(pixel: Rgba8) -> Color {
+    Color::from_rgba(pixel[0], pixel[1], pixel[2], pixel[3])
+}
+
+/// Composites `color` over `base` with straight alpha, in integer math.
+pub fn composite_rgba8(base: Rgba8, color: Rgba8) -> Rgba8 {
+    let a = color[3] as u32;
+    if a == 255 {
+        return color;
+    }
+    if a == 0 {
+        return base;
+    }
+    // Both alphas scaled by 255
+    let base_a = base[3] as u32 * (255 - a);
+    let out_a = a * 255 + base_a;
+    let mix = |c: u8, b: u8| ((c as u32 * a * 255 + b as u32 * base_a + out_a / 2) / out_a) as u8;
+    [
+        mix(color[0], base[0]),
+        mix(color[1], base[1]),
+        mix(color[2], base[2]),
+        ((out_a + 127) / 255) as u8,
+    ]
+}
+
+/// Composites `color` over `base` with the alpha of `color` scaled by
+/// `coverage`.
+pub fn composite(base: Color, color: Color, coverage: f32) -> Color {
+    let a = color.a * coverage.clamp(0.0, 1.0);
+    if a <= 0.0 {
+        return base;
     }
+    let out_a = a + base.a * (1.0 - a);
+    let mix = |c: f32, b: f32| (c * a + b * base.a * (1.0 - a)) / out_a;
+    Color::new(
+        mix(color.r, base.r),
+        mix(color.g, base.g),
+        mix(color.b, base.b),
+        out_a,
+    )
 }
 
+/// Cloning a layer is cheap, since the copy shares pixel tiles with the
+/// original until either of them paints over one.
+#[derive(Clone)]
 pub struct Layer {
     pub rect: ImageRect,
-    pub data: Vec<Color>,
+    /// Sparse tiles, so pixels that were never painted take no memory.
+    pub data: TiledPixels,
+    /// Palette indices in indexed mode, with `None` for transparent pixels.
+    /// Empty in RGBA mode. `data` then holds the palette colors the indices
+    /// point to, so drawing and compositing work the same in either mode.
+    /// Writing through `draw_pixel` or `set_pixel` snaps to `palette` and
+    /// keeps the two in step.
+    pub indices: TiledPixels<Option<u8>>,
+    /// The image palette's colors in indexed mode, shared between layers.
+    /// Empty in RGBA mode.
+    pub palette: Arc<[[u8; 3]]>,
+    /// 16-bit channels in 16-bit mode, empty otherwise. `data` then holds
+    /// them rounded to bytes for display and for tools that work on bytes.
+    /// Writing through `draw_pixel` or `set_pixel` keeps the two in step.
+    pub deep: TiledPixels<Rgba16>,
     pub z_index: i32,
     pub dirty_rect: ImageRect,
 }
@@ -74,6 +212,9 @@ pub struct Layer {
 pub struct Image {
     pub rect: ImageRect,
     pub layers: Vec<Layer>,
+    /// The palette every layer indexes into, if the image is in indexed mode.
+    pub palette: Option<Palette>,
+    pub depth: BitDepth,
 }
 
 pub struct ImageHistory {
@@ -83,36 +224,37 @@ pub struct ImageHistory {
 
 impl Layer {
     pub fn new(rect: ImageRect) -> Self {
-        let color = app::WHITE;
-        let data = vec![color; (rect.w * rect.h) as usize];
+        let data = TiledPixels::new(rect.w, rect.h, to_rgba8(app::WHITE));
         Self {
             rect,
             data,
+            indices: TiledPixels::default(),
+            palette: Arc::default(),
+            deep: TiledPixels::default(),
             z_index: 0,
             dirty_rect: ImageRect::new(0, 0, 0, 0),
         }
     }
 
     pub fn from_path(x: i32, y: i32, path: &str) -> Result<Self, ImageError> {
-        let image = image::open(path)?.to_rgba8();
-        let rect = ImageRect::new(x, y, image.width(), image.height());
+        Ok(Self::from_image(x, y, &image::open(path)?))
+    }
 
-        let color = app::WHITE;
-        let mut data = vec![color; (rect.w * rect.h) as usize];
+    pub fn from_image(x: i32, y: i32, image: &DynamicImage) -> Self {
+        let image = image.to_rgba8();
+        let rect = ImageRect::new(x, y, image.width(), image.height());
 
-        for y in 0..rect.h {
-            for x in 0..rect.w {
-                let c = image.get_pixel(x as u32, y as u32);
-                data[(y * rect.w + x) as usize] = Color::new(c[0] as f32 / 255.0, c[1] as f32 / 255.0, c[2] as f32 / 255.0, c[3] as f32 / 255.0);
-            }
-        }
+        let data = TiledPixels::from_vec(rect.w, rect.h, image.pixels().map(|p| p.0).collect());
 
-        Ok(Self {
+        Self {
             rect,
             data,
+            indices: TiledPixels::default(),
+            palette: Arc::default(),
+            deep: TiledPixels::default(),
             z_index: 0,
             dirty_rect: ImageRect::new(0, 0, 0, 0),
-        })
+        }
     }
 
     pub fn contains_point(&self, x: i32, y: i32) -> bool {
@@ -121,84 +263,146 @@ impl Layer {
 
     pub fn draw_pixel(&mut self, x: i32, y: i32, color: Color) {
         if self.contains_point(x, y) {
-            self.data[y as usize * self.rect.w as usize + x as usize] = color;
+            self.draw_pixel_unchecked(x, y, color);
         }
     }
 
     pub fn draw_pixel_unchecked(&mut self, x: i32, y: i32, color: Color) {
-        self.data[y as usize * self.rect.w as usize + x as usize] = color;
+        if !self.indices.is_empty() {
+            self.set_pixel(x as u32, y as u32, to_rgba8(color));
+        } else if self.deep.is_empty() {
+            self.data.set(x as u32, y as u32, to_rgba8(color));
+        } else {
+            // Rounded from the 16-bit value so the two always agree
+            let pixel = depth::to_rgba16(color);
+            self.deep.set(x as u32, y as u32, pixel);
+            self.data.set(x as u32, y as u32, depth::narrow(pixel));
+        }
     }
 
-    pub fn draw_line(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
-        self.draw_pixel(x1, y1, color);
-        self.draw_pixel(x2, y2, color);
-        let w = (x2 - x1).abs();
-        let h = (y2 - y1).abs();
-        let step = std::cmp::max(w, h);
-        if step != 0 {
-            let dx = (x2 as f64 - x1 as f64) / step as f64;
-            let dy = (y2 as f64 - y1 as f64) / step as f64;
-            for i in 0..step {
-                self.draw_pixel((x1 as f64 + dx * i as f64) as i32, (y1 as f64 + dy * i as f64) as i32, color);
+    /// Writes a pixel as bytes. In 16-bit mode the 16-bit channels are widened
+    /// from it, unless they already round to the same bytes. In indexed mode
+    /// it's snapped to the nearest palette color, or made transparent if it's
+    /// less than half opaque. Anything that writes a layer's pixels directly
+    /// should go through here so they never disagree.
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: Rgba8) {
+        if !self.indices.is_empty() {
+            let [r, g, b, a] = pixel;
+            let index = (a >= 128).then(|| quantize::nearest_color(&self.palette, [r, g, b]) as u8);
+            self.set_index(x, y, index);
+            return;
+        }
+        if !self.deep.is_empty() && depth::narrow(self.deep.get(x, y)) != pixel {
+            self.deep.set(x, y, depth::widen(pixel));
+        }
+        self.data.set(x, y, pixel);
+    }
+
+    /// Points a pixel at a palette entry, or at none to make it transparent,
+    /// and repaints it with that entry's color. Only for indexed mode.
+    pub fn set_index(&mut self, x: u32, y: u32, index: Option<u8>) {
+        self.indices.set(x, y, index);
+        let pixel = match index {
+            Some(i) => {
+                let [r, g, b] = self.palette[i as usize];
+                [r, g, b, 255]
             }
+            None => TRANSPARENT,
+        };
+        self.data.set(x, y, pixel);
+    }
+
+    pub fn draw_line(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+        for (x, y) in line_points(x1, y1, x2, y2) {
+            self.draw_pixel(x, y, color);
         }
         self.add_dirty_rect(ImageRect {
             x: min(x1, x2) - 1,
             y: min(y1, y2) - 1,
-            w: w as u32 + 2,
-            h: h as u32 + 2,
+            w: (x2 - x1).unsigned_abs() + 2,
+            h: (y2 - y1).unsigned_abs() + 2,
         });
     }
 
     pub fn get_pixel(&self, x: i32, y: i32) -> Option<Color> {
         if self.contains_point(x, y) {
-            return Some(self.data[y as usize * self.rect.w as usize + x as usize]);
+            return Some(self.get_pixel_unchecked(x, y));
         }
         None
     }
 
     pub fn get_pixel_unchecked(&self, x: i32, y: i32) -> Color {
-        self.data[y as usize * self.rect.w as usize + x as usize]
+        if self.deep.is_empty() {
+            from_rgba8(self.data.get(x as u32, y as u32))
+        } else {
+            depth::from_rgba16(self.deep.get(x as u32, y as u32))
+        }
     }
 
     pub fn fill(&mut self, x: i32, y: i32, color: Color) {
-        if let Some(target_color) = self.get_pixel(x, y) {
-            if target_color == color {
-                return;
+        self.fill_with(x, y, color, &FillOptions::default(), None);
+    }
+
+    /// Flood fills from (x, y). If `sample` is given, the region is found on it
+    /// instead of this layer; it must be the same size as this layer.
+    pub fn fill_with(&mut self, x: i32, y: i32, color: Color, options: &FillOptions, sample: Option<&Layer>) {
+        let region = match fill::find_region(sample.unwrap_or(self), x, y, options) {
+            Some(region) => region,
+            None => return,
+        };
+        self.fill_region(&region, color);
+    }
+
+    /// Flood fills like `fill_with`, but paints the region with `pattern`
+    /// tiled from the origin `align` gives.
+    pub fn fill_pattern(&mut self, x: i32, y: i32, pattern: &Stamp, align: PatternAlign, options: &FillOptions, sample: Option<&Layer>) {
+        let region = match fill::find_region(sample.unwrap_or(self), x, y, options) {
+            Some(region) => region,
+            None => return,
+        };
+        let origin = align.origin(self.rect, x, y);
+        self.fill_region_with(&region, |px, py| fill::pattern_color(pattern, origin, px, py));
+    }
+
+    pub fn fill_region(&mut self, region: &FillRegion, color: Color) {
+        self.fill_region_with(region, |_, _| color);
+    }
+
+    /// Paints every pixel of `region` with the color `paint` returns for it.
+    pub fn fill_region_with<F: Fn(i32, i32) -> Color>(&mut self, region: &FillRegion, paint: F) {
+        let bounds = region.bounds;
+        for y in bounds.y..bounds.y + bounds.h as i32 {
+            for x in bounds.x..bounds.x + bounds.w as i32 {
+                if region.contains(x, y) {
+                    self.draw_pixel(x, y, paint(x, y));
+                }
             }
-            self.draw_pixel(x, y, color);
-            let mut queue = VecDeque::new();
-            queue.push_back((x, y));
-            while !queue.is_empty() {
-                if let Some((x, y)) = queue.pop_front() {
-                    if let Some(old_color) = self.get_pixel(x - 1, y) {
-                        if old_color == target_color {
-                            self.draw_pixel(x - 1, y, color);
-                            queue.push_back((x - 1, y));
-                        }
-                    }
-                    if let Some(old_color) = self.get_pixel(x + 1, y) {
-                        if old_color == target_color {
-                            self.draw_pixel(x + 1, y, color);
-                            queue.push_back((x + 1, y));
-                        }
-                    }
-                    if let Some(old_color) = self.get_pixel(x, y - 1) {
-                        if old_color == target_color {
-                            self.draw_pixel(x, y - 1, color);
-                            queue.push_back((x, y - 1));
-                        }
-                    }
-                    if let Some(old_color) = self.get_pixel(x, y + 1) {
-                        if old_color == target_color {
-                            self.draw_pixel(x, y + 1, color);
-                            queue.push_back((x, y + 1));
-                        }
-                    }
+        }
+        self.add_dirty_rect(bounds);
+    }
+
+    /// Returns a copy of the pixels inside `rect`, given in this layer's
+    /// coordinates. Pixels outside the layer are transparent.
+    pub fn crop(&self, rect: ImageRect) -> Layer {
+        let mut layer = Layer::new(rect);
+        layer.data.fill(TRANSPARENT);
+        if !self.deep.is_empty() {
+            layer.deep = TiledPixels::new(rect.w, rect.h, [0; 4]);
+        }
+        let source = rect.intersection(ImageRect::new(0, 0, self.rect.w, self.rect.h));
+        for y in source.y..source.y + source.h as i32 {
+            for x in source.x..source.x + source.w as i32 {
+                let pixel = self.data.get(x as u32, y as u32);
+                // Leave clear tiles unallocated
+                if pixel != TRANSPARENT {
+                    layer.data.set((x - rect.x) as u32, (y - rect.y) as u32, pixel);
+                }
+                if !self.deep.is_empty() {
+                    layer.deep.set((x - rect.x) as u32, (y - rect.y) as u32, self.deep.get(x as u32, y as u32));
                 }
             }
         }
-        self.add_dirty_rect(self.rect);
+        layer
     }
 
     pub fn blend(&mut self, other: &Layer, clip_rect: ImageRect) -> bool {
@@ -209,36 +413,33 @@ impl Layer {
             return false;
         }
 
-        for y in target_rect.y..target_rect.y + target_rect.h as i32 {
-            for x in target_rect.x..target_rect.x + target_rect.w as i32 {
-                if !(self.rect.contains(x, y) && other.rect.contains(x - other.rect.x, y - other.rect.y)) {
-                }
-                let base_color = self.get_pixel_unchecked(x, y);
-                let other_color = other.get_pixel_unchecked(x - other.rect.x, y - other.rect.y);
+        self.composite_rect(other, target_rect);
 
-                if other_color.a == 0.0 {
-                    continue;
-                }
-                if other_color.a == 1.0 {
-                    self.draw_pixel(x, y, other_color);
-                    continue;
-                }
+        return true;
+    }
 
-                let a1 = other_color.a;
-                let a2 = base_color.a;
-                let factor = a2 * (1.0 - a1);
-
-                let new_color = Color {
-                    r: (base_color.r * a1 + other_color.r * factor / (a1 + factor)),
-                    g: (base_color.r * a1 + other_color.r * factor / (a1 + factor)),
-                    b: (base_color.r * a1 + other_color.r * factor / (a1 + factor)),
-                    a: (base_color.r * a1 + other_color.r * factor / (a1 + factor)),
-                };
-                self.draw_pixel(x, y, new_color);
-            }
+    /// Composites `other` over the pixels inside `rect`, given in image
+    /// coordinates. Tiles are blended in parallel, and any that `other` leaves
+    /// clear are skipped. Only bytes are blended, so this layer can't have
+    /// 16-bit channels.
+    pub fn composite_rect(&mut self, other: &Layer, rect: ImageRect) {
+        debug_assert!(self.deep.is_empty(), "composite_rect only blends bytes");
+        let rect = rect.intersection(self.rect).intersection(other.rect);
+        if rect.w == 0 || rect.h == 0 {
+            return;
         }
-
-        return true;
+        let local = ImageRect::new(rect.x - self.rect.x, rect.y - self.rect.y, rect.w, rect.h);
+        // From this layer's coordinates to those of `other`
+        let (dx, dy) = (self.rect.x - other.rect.x, self.rect.y - other.rect.y);
+        let wanted = |part: ImageRect| !other.data.is_clear(ImageRect::new(part.x + dx, part.y + dy, part.w, part.h));
+        self.data.par_paint(local, wanted, |part, tile| {
+            for y in part.y..part.y + part.h as i32 {
+                for x in part.x..part.x + part.w as i32 {
+                    let pixel = other.data.get((x + dx) as u32, (y + dy) as u32);
+                    tile.set(x as u32, y as u32, composite_rgba8(tile.get(x as u32, y as u32), pixel));
+                }
+            }
+        });
     }
 
     pub fn add_dirty_rect(&mut self, rect: ImageRect) {
@@ -258,20 +459,28 @@ impl Image {
         Self {
             rect,
             layers,
+            palette: None,
+            depth: BitDepth::Eight,
         }
     }
 
+    /// Opens an image as a single layer. Images with 16-bit channels open in
+    /// 16-bit mode.
     pub fn from_path(path: &str) -> Result<Self, ImageError> {
-        let mut layers = Vec::new();
-        layers.push(Layer::from_path(0, 0, path)?);
+        let source = image::open(path)?;
+        let color = source.color();
+        let mut layer = Layer::from_image(0, 0, &source);
+        let depth = if color.bytes_per_pixel() / color.channel_count() == 2 {
+            layer.deep = TiledPixels::from_pixels(layer.rect.w, layer.rect.h, [0; 4], source.to_rgba16().pixels().map(|p| p.0));
+            BitDepth::Sixteen
+        } else {
+            BitDepth::Eight
+        };
         Ok(Self {
-            rect: ImageRect::new(
-                0,
-                0,
-                layers[0].rect.w,
-                layers[0].rect.h,
-            ),
-            layers,
+            rect: ImageRect::new(0, 0, layer.rect.w, layer.rect.h),
+            layers: vec![layer],
+            palette: None,
+            depth,
         })
     }
 
@@ -290,6 +499,13 @@ impl Image {
         // TODO
     }
 
+    /// Inserts a copy of the layer at `index` just above it.
+    pub fn duplicate_layer(&mut self, index: usize) {
+        let mut layer = self.layers[index].clone();
+        layer.add_dirty_rect(ImageRect::new(0, 0, layer.rect.w, layer.rect.h));
+        self.layers.insert(index + 1, layer);
+    }
+
     pub fn take_snapshot(&self, history: &mut ImageHistory) {
         // TODO
     }
@@ -302,10 +518,31 @@ impl Image {
         // TODO
     }
 
+    /// Composites every layer over a white background, inside `clip_rect`
+    /// only. The returned layer covers just the clipped part of the image.
     pub fn blend(&self, clip_rect: ImageRect) -> Layer {
+        let rect = self.rect.intersection(clip_rect);
+        let mut base = Layer::new(rect);
+        // Everything in the clip rect is wanted, not just what's dirty
+        base.add_dirty_rect(rect);
+        for layer in &self.layers {
+            base.blend(layer, rect);
+        }
+        base
+    }
+
+    /// Composites every layer over a transparent background, ignoring dirty
+    /// rects.
+    pub fn flatten(&self) -> Layer {
+        self.flatten_over(TRANSPARENT)
+    }
+
+    /// Composites every layer over `background`, ignoring dirty rects.
+    pub fn flatten_over(&self, background: Rgba8) -> Layer {
         let mut base = Layer::new(self.rect);
+        base.data.fill(background);
         for layer in &self.layers {
-            base.blend(layer, clip_rect);
+            base.composite_rect(layer, self.rect);
         }
         base
     }
@@ -313,37 +550,13 @@ impl Image {
     pub fn raw_data(&self) -> Vec<u8> {
 
         let blended = self.blend(self.rect);
-
-        let mut raw_data = vec![0; blended.rect.w as usize * blended.rect.h as usize * 4];
-        for y in 0..blended.rect.h {
-            for x in 0..blended.rect.w {
-                let p = y as usize * blended.rect.w as usize + x as usize;
-                let color = blended.data[p];
-                raw_data[p * 4 + 0] = (color.r * 255.0) as u8;
-                raw_data[p * 4 + 1] = (color.g * 255.0) as u8;
-                raw_data[p * 4 + 2] = (color.b * 255.0) as u8;
-                raw_data[p * 4 + 3] = (color.a * 255.0) as u8;
-            }
-        }
-        return raw_data;
+        return blended.data.to_bytes();
     }
 
+    /// The composited pixels inside `rect`, which must lie within the image.
     pub fn partial_data(&self, rect: ImageRect) -> Vec<u8> {
         let blended = self.blend(rect);
-        let mut raw_data = vec![0; rect.w as usize * rect.h as usize * 4];
-        for y in rect.y..rect.y+rect.h as i32 {
-            for x in rect.x..rect.x+rect.w as i32 {
-                let si = (y - rect.y) as usize * rect.w as usize + (x - rect.x) as usize;
-                let di = y as usize * blended.rect.w as usize + x as usize;
-                let color = blended.data[di];
-                raw_data[si * 4 + 0] = (color.r * 255.0) as u8;
-                raw_data[si * 4 + 1] = (color.g * 255.0) as u8;
-                raw_data[si * 4 + 2] = (color.b * 255.0) as u8;
-                raw_data[si * 4 + 3] = (color.a * 255.0) as u8;
-
-            }
-        }
-        return raw_data;
+        return blended.data.to_bytes();
     }
 
     pub fn dirty_rect(&self) -> ImageRect {
@@ -360,28 +573,163 @@ impl Image {
         }
     }
 
-    pub fn save(&self, path: &Path) -> Result<(), ()> {
+    /// Saves the image flattened over white, the way it's shown on the canvas.
+    pub fn save(&self, path: &Path) -> Result<(), ImageError> {
+        self.save_over(path, to_rgba8(app::WHITE))
+    }
 
-        let blended = self.blend(self.rect);
-        println!("save self rect {:?}", self.rect);
-        let mut image = RgbaImage::from_pixel(blended.rect.w, blended.rect.h, [255, 255, 255, 255].into());
-        for y in 0..blended.rect.h {
-            for x in 0..blended.rect.w {
-                let color = blended.get_pixel(x as i32, y as i32).unwrap();
-                println!("color {:?}", color);
-                image.put_pixel(x as u32, y as u32, [
-                    (color.r * 255.0) as u8,
-                    (color.g * 255.0) as u8,
-                    (color.b * 255.0) as u8,
-                    (color.a * 255.0) as u8,
-                ].into());
+    /// Saves the image flattened over `background`, which keeps transparency
+    /// if it's `TRANSPARENT`. 16-bit images are saved with 16 bits per channel.
+    pub fn save_over(&self, path: &Path, background: Rgba8) -> Result<(), ImageError> {
+        if self.depth == BitDepth::Sixteen {
+            return self.save_16bit(path, depth::widen(background));
+        }
+
+        let flat = self.flatten_over(background);
+        let image = RgbaImage::from_raw(flat.rect.w, flat.rect.h, flat.data.to_bytes())
+            .expect("layer data matches its rect");
+        image.save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn line_pixels(layer: &Layer) -> Vec<usize> {
+        let background = to_rgba8(app::WHITE);
+        (0..layer.data.len()).filter(|&i| layer.data[i] != background).collect()
+    }
+
+    #[test]
+    fn empty_rects_are_ignored_by_union_and_never_intersect() {
+        let rect = ImageRect::new(-4, 2, 10, 6);
+        let empty = ImageRect::new(100, -50, 0, 7);
+        assert_eq!(rect.union(empty), rect);
+        assert_eq!(empty.union(rect), rect);
+        assert!(!rect.has_intersection(ImageRect::new(0, 4, 0, 0)));
+        assert!(rect.intersection(ImageRect::new(0, 4, 3, 0)).is_empty());
+        assert!(!empty.contains(100, -50));
+    }
+
+    #[test]
+    fn touching_rects_do_not_intersect() {
+        let rect = ImageRect::new(0, 0, 10, 10);
+        for other in [ImageRect::new(10, 0, 5, 5), ImageRect::new(0, 10, 5, 5), ImageRect::new(-5, -5, 5, 5), ImageRect::new(10, 10, 1, 1)] {
+            assert!(!rect.has_intersection(other), "{:?}", other);
+            assert_eq!(rect.intersection(other), ImageRect::new(0, 0, 0, 0));
+        }
+        assert_eq!(rect.intersection(ImageRect::new(9, 9, 5, 5)), ImageRect::new(9, 9, 1, 1));
+    }
+
+    #[test]
+    fn negative_coordinates() {
+        let a = ImageRect::new(-10, -10, 20, 20);
+        let b = ImageRect::new(-5, -20, 3, 40);
+        assert_eq!(a.intersection(b), ImageRect::new(-5, -10, 3, 20));
+        assert_eq!(a.union(b), ImageRect::new(-10, -20, 20, 40));
+        assert!(a.contains(-10, -10) && !a.contains(10, 0) && !a.contains(0, -11));
+    }
+
+    fn rect_strategy() -> impl Strategy<Value = ImageRect> {
+        (-200..200i32, -200..200i32, 0..100u32, 0..100u32).prop_map(|(x, y, w, h)| ImageRect::new(x, y, w, h))
+    }
+
+    proptest! {
+        #[test]
+        fn intersection_covers_exactly_the_shared_pixels(a in rect_strategy(), b in rect_strategy(), px in -250..250i32, py in -250..250i32) {
+            let both = a.intersection(b);
+            prop_assert_eq!(both, b.intersection(a));
+            prop_assert_eq!(a.has_intersection(b), !both.is_empty());
+            prop_assert_eq!(both.contains(px, py), a.contains(px, py) && b.contains(px, py));
+        }
+
+        #[test]
+        fn union_covers_both_rects(a in rect_strategy(), b in rect_strategy(), px in -250..250i32, py in -250..250i32) {
+            let either = a.union(b);
+            prop_assert_eq!(either, b.union(a));
+            if a.contains(px, py) || b.contains(px, py) {
+                prop_assert!(either.contains(px, py));
+            }
+            if !a.is_empty() && !b.is_empty() {
+                prop_assert_eq!(either.x, a.x.min(b.x));
+                prop_assert_eq!(either.right(), a.right().max(b.right()));
             }
         }
-        println!("done saving");
 
-        match image.save(path) {
-            Ok(()) => Ok(()),
-            Err(_) => Err(()),
+        #[test]
+        fn a_rect_intersected_with_itself_is_unchanged(a in rect_strategy()) {
+            let expected = if a.is_empty() { ImageRect::new(0, 0, 0, 0) } else { a };
+            prop_assert_eq!(a.intersection(a), expected);
+        }
+    }
+
+    #[test]
+    fn line_points_include_endpoints_and_are_connected() {
+        let points = line_points(2, 3, 17, -4);
+        assert_eq!(points.first(), Some(&(2, 3)));
+        assert_eq!(points.last(), Some(&(17, -4)));
+        for pair in points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert!((a.0 - b.0).abs() <= 1 && (a.1 - b.1).abs() <= 1);
+            assert_ne!(a, b);
+        }
+        assert_eq!(points.len(), 16);
+    }
+
+    #[test]
+    fn duplicate_layers_share_tiles_until_painted() {
+        let mut image = Image::new(256, 256);
+        image.layers[0].fill(0, 0, app::BLACK);
+        image.duplicate_layer(0);
+        image.layers[1].draw_pixel(0, 0, app::WHITE);
+        assert_eq!(image.layers[0].get_pixel(0, 0), Some(app::BLACK));
+        assert_eq!(image.layers[1].get_pixel(0, 0), Some(app::WHITE));
+        assert_eq!(image.layers[1].get_pixel(200, 200), Some(app::BLACK));
+    }
+
+    #[test]
+    fn saves_over_white_unless_given_a_background() {
+        let mut image = Image::new(2, 1);
+        image.layers[0].data.fill(TRANSPARENT);
+        image.layers[0].set_pixel(0, 0, [255, 0, 0, 255]);
+        let path = std::env::temp_dir().join("pixel_editor_layer_save_test.png");
+        image.save(&path).unwrap();
+        let white = image::open(&path).unwrap().to_rgba8();
+        image.save_over(&path, TRANSPARENT).unwrap();
+        let clear = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(white.into_raw(), vec![255, 0, 0, 255, 255, 255, 255, 255]);
+        assert_eq!(clear.into_raw(), vec![255, 0, 0, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn compositing_is_the_same_on_any_number_of_threads() {
+        let mut image = Image::new(300, 200);
+        let mut layer = Layer::new(ImageRect::new(-37, 21, 250, 250));
+        layer.data.fill(TRANSPARENT);
+        for i in 0..100 {
+            layer.draw_line(i * 3, 0, 249 - i, 249, Color::from_rgba(i as u8, 200, 50, 100 + i as u8));
+        }
+        image.layers.push(layer);
+        let single = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let many = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        assert!(single.install(|| image.raw_data()) == many.install(|| image.raw_data()));
+    }
+
+    #[test]
+    fn line_is_symmetric() {
+        for &(x1, y1) in &[(0, 0), (5, 5), (3, 9), (12, 1)] {
+            for x2 in 0..16 {
+                for y2 in 0..16 {
+                    let mut forward = Layer::new(ImageRect::new(0, 0, 16, 16));
+                    let mut backward = Layer::new(ImageRect::new(0, 0, 16, 16));
+                    forward.draw_line(x1, y1, x2, y2, app::BLACK);
+                    backward.draw_line(x2, y2, x1, y1, app::BLACK);
+                    assert_eq!(line_pixels(&forward), line_pixels(&backward), "({}, {}) -> ({}, {})", x1, y1, x2, y2);
+                }
+            }
         }
     }
 }
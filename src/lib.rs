@@ -0,0 +1,22 @@
+pub mod app;
+#[cfg(not(feature = "gui"))]
+mod color;
+pub mod layer;
+#[cfg(feature = "gui")]
+pub mod ui;
+pub mod stroke;
+pub mod brush;
+pub mod fill;
+pub mod spray;
+pub mod gradient;
+pub mod util;
+pub mod palette;
+pub mod quantize;
+pub mod indexed;
+pub mod depth;
+#[cfg(feature = "gui")]
+pub mod canvas;
+pub mod tiles;
+pub mod composite;
+pub mod transform;
+pub mod picker;
@@ -1,11 +1,42 @@
-mod app;
-use app::{self as g, Key, Color, Rect, Vec2};
+use pixel_editor::{color, rect, vec2, temp_style, push_style};
 
-mod layer;
-use layer::{Image, Layer, ImageRect};
+use pixel_editor::app::{self as g, Key, Color, Rect, Vec2};
 
-mod ui;
-use ui::{Ui, Layout, StyleInfo};
+use pixel_editor::layer::{self, Image, Layer, ImageRect};
+
+use std::path::Path;
+
+use pixel_editor::ui::{Ui, Layout, StyleInfo, WidgetFill};
+
+use pixel_editor::stroke::Stroke;
+
+use pixel_editor::brush::{Brush, BrushShape, BrushStroke, BrushLibrary, Stamp};
+
+use pixel_editor::fill::{self, FillOptions, ColorDistance, Connectivity, PatternAlign, ReplaceScope};
+
+use pixel_editor::spray::{SprayCan, SprayDistribution};
+
+use pixel_editor::gradient::{Gradient, GradientShape};
+
+use pixel_editor::palette::Palette;
+
+use pixel_editor::quantize::{self, QuantizeMethod, Dither};
+
+use pixel_editor::indexed::MAX_INDEXED_COLORS;
+
+use pixel_editor::depth::BitDepth;
+
+use pixel_editor::canvas::TiledTexture;
+
+use pixel_editor::composite::CompositeCache;
+
+use pixel_editor::tiles::TiledPixels;
+
+use pixel_editor::picker::{ColorPicker, SampleSource};
+
+use pixel_editor::util;
+
+const BRUSH_LIBRARY_DIR: &str = "brushes";
 
 // use std::path::Path;
 // use nfd::Response as FileDialogResponse;
@@ -23,9 +54,52 @@ struct State {
     canvas_scale: f32,
     canvas_offset: Vec2,
     canvas_offset_baseline: Vec2,
-    active_color: Color,
+    foreground_color: Color,
+    background_color: Color,
+    picker_edits_background: bool,
+    /// The picker's hue, saturation and value, kept separately from the color
+    /// so hue and saturation survive dragging through grays and black.
+    picker_hsv: (f32, f32, f32),
+    picker_color: Color,
     active_tool: String,
+    stroke: Stroke,
+    brush: Brush,
+    brush_stroke: BrushStroke,
+    stamp_path: String,
+    brush_library: BrushLibrary,
+    spray_can: SprayCan,
+    fill_options: FillOptions,
+    fill_pattern: Option<Stamp>,
+    pattern_align: PatternAlign,
+    pattern_path: String,
+    gradient: Gradient,
+    gradient_use_ramp: bool,
+    gradient_ramp: Vec<Color>,
+    palette: Palette,
+    palette_selected: Option<usize>,
+    palette_path: String,
+    quantize_colors: usize,
+    quantize_method: QuantizeMethod,
+    quantize_dither: Dither,
+    export_path: String,
+    /// Snap everything drawn to the nearest color in the palette.
+    palette_lock: bool,
+    /// The active layer as it was when the current stroke started, so palette
+    /// lock only snaps pixels the stroke changed. Shares tiles with the layer
+    /// until the stroke paints them.
+    stroke_snapshot: Option<TiledPixels>,
+    replace_tolerance: f32,
+    replace_distance: ColorDistance,
+    replace_scope: ReplaceScope,
+    selection: Option<ImageRect>,
+    stroke_start: (i32, i32),
+    stroke_end: (i32, i32),
     currently_drawing: bool,
+    drawing_with_background: bool,
+    /// The current stroke is an Alt-click pick rather than a use of the
+    /// active tool.
+    picking: bool,
+    color_picker: ColorPicker,
     showing_new_dialog: bool,
     showing_open_dialog: bool,
     showing_save_dialog: bool,
@@ -44,9 +118,44 @@ impl State {
             canvas_scale: 2.0,
             canvas_offset: vec2!(0, 0),
             canvas_offset_baseline: vec2!(0, 0),
-            active_color: g::BLACK,
+            foreground_color: g::BLACK,
+            background_color: g::WHITE,
+            picker_edits_background: false,
+            picker_hsv: (0.0, 0.0, 0.0),
+            picker_color: g::BLACK,
             active_tool: "Paintbrush".into(),
+            stroke: Stroke::new(),
+            brush: Brush::new(),
+            brush_stroke: BrushStroke::new(),
+            stamp_path: "".into(),
+            brush_library: BrushLibrary::load(Path::new(BRUSH_LIBRARY_DIR)),
+            spray_can: SprayCan::new(),
+            fill_options: FillOptions::default(),
+            fill_pattern: None,
+            pattern_align: PatternAlign::Canvas,
+            pattern_path: "".into(),
+            gradient: Gradient::new(),
+            gradient_use_ramp: false,
+            gradient_ramp: Vec::new(),
+            palette: Palette::default(),
+            palette_selected: None,
+            palette_path: "".into(),
+            quantize_colors: 16,
+            quantize_method: QuantizeMethod::MedianCut,
+            quantize_dither: Dither::None,
+            export_path: "".into(),
+            palette_lock: false,
+            stroke_snapshot: None,
+            replace_tolerance: 0.0,
+            replace_distance: ColorDistance::PerChannel,
+            replace_scope: ReplaceScope::Layer,
+            selection: None,
+            stroke_start: (0, 0),
+            stroke_end: (0, 0),
             currently_drawing: false,
+            drawing_with_background: false,
+            picking: false,
+            color_picker: ColorPicker::new(),
             showing_new_dialog: false,
             showing_open_dialog: false,
             showing_save_dialog: false,
@@ -77,6 +186,68 @@ impl State {
      fn active_layer(&mut self) -> &mut Layer {
          &mut self.image.layers[self.active_layer_idx]
      }
+
+     fn swap_colors(&mut self) {
+         std::mem::swap(&mut self.foreground_color, &mut self.background_color);
+     }
+
+     /// The color painted by the current stroke: the background color for
+     /// right-button strokes, the foreground color otherwise.
+     fn stroke_color(&self) -> Color {
+         if self.drawing_with_background {
+             self.background_color
+         } else {
+             self.foreground_color
+         }
+     }
+
+     /// Sets the foreground color, or the background color for right-button
+     /// strokes, to the picker's sample at (x, y) in the active layer's
+     /// coordinates, and selects the matching palette swatch if there is one.
+     fn pick_color(&mut self, x: i32, y: i32) {
+         let layer_rect = self.active_layer().rect;
+         let Some(color) = self.color_picker.sample(&self.image, self.active_layer_idx, x + layer_rect.x, y + layer_rect.y) else {
+             return;
+         };
+         if self.drawing_with_background {
+             self.background_color = color;
+         } else {
+             self.foreground_color = color;
+         }
+         let rgba = util::color_to_rgba8(color);
+         if let Some(i) = self.palette.colors.iter().position(|c| util::color_to_rgba8(*c) == rgba) {
+             self.palette_selected = Some(i);
+         }
+     }
+
+     /// Replaces `from` with `to` in the pixels covered by the Color Replace
+     /// tool's scope.
+     fn replace_color(&mut self, from: Color, to: Color) {
+         let (tolerance, distance) = (self.replace_tolerance, self.replace_distance);
+         match self.replace_scope {
+             ReplaceScope::Layer => {
+                 fill::replace_color(self.active_layer(), from, to, tolerance, distance, None);
+             }
+             ReplaceScope::Selection => match self.layer_selection() {
+                 Some(clip) => {
+                     fill::replace_color(self.active_layer(), from, to, tolerance, distance, Some(clip));
+                 }
+                 None => self.error_text = "Select a region to replace colors in.".into(),
+             },
+             ReplaceScope::AllLayers => {
+                 for layer in &mut self.image.layers {
+                     fill::replace_color(layer, from, to, tolerance, distance, None);
+                 }
+             }
+         }
+     }
+
+     /// The selection in the active layer's coordinates.
+     fn layer_selection(&mut self) -> Option<ImageRect> {
+         let selection = self.selection?;
+         let layer_rect = self.active_layer().rect;
+         Some(ImageRect::new(selection.x - layer_rect.x, selection.y - layer_rect.y, selection.w, selection.h))
+     }
 }
 
 fn draw_tool_pane(ui: &mut Ui, state: &mut State) {
@@ -85,11 +256,14 @@ fn draw_tool_pane(ui: &mut Ui, state: &mut State) {
     ui.push_layout("Tool columns", Layout::ToolColumn);
 
     let tools = [
+        "Select",
         "Pencil",
         "Paintbrush",
         "Color Picker",
         "Paint Bucket",
         "Spray Can",
+        "Gradient",
+        "Color Replace",
     ];
     for tool in &tools {
         if state.active_tool == *tool {
@@ -99,55 +273,809 @@ fn draw_tool_pane(ui: &mut Ui, state: &mut State) {
             state.active_tool = String::from(*tool);
         }
     }
+
+    ui.push_layout("Color swatch row", Layout::ToolRow);
+    temp_style!(ui, background_color: state.foreground_color);
+    ui.button("##foreground");
+    temp_style!(ui, background_color: state.background_color);
+    ui.button("##background");
+    if ui.button("Swap").clicked {
+        state.swap_colors();
+    }
+    ui.pop_layout();
 }
 
-fn draw_color_selector(ui: &mut Ui, state: &mut State) {
-    ui.push_window("Color Selector", rect!(200, 50, 100, 300));
-    ui.push_layout("Color columns", Layout::ToolColumn);
+fn draw_pencil_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Pencil Settings", rect!(50, 400, 250, 100));
+    ui.push_layout("Pencil columns", Layout::ToolColumn);
+
+    if state.stroke.pixel_perfect {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Pixel Perfect").clicked {
+        state.stroke.pixel_perfect = !state.stroke.pixel_perfect;
+    }
+}
+
+fn draw_brush_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Brush Settings", rect!(50, 400, 250, 500));
+    ui.push_layout("Brush columns", Layout::ToolColumn);
+
+    let brush = &mut state.brush;
+
+    ui.push_layout("Brush shape row", Layout::ToolRow);
+    let is_round = matches!(brush.shape, BrushShape::Round);
+    let is_square = matches!(brush.shape, BrushShape::Square);
+    let is_stamp = matches!(brush.shape, BrushShape::Stamp(_));
+    if is_round {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Round").clicked {
+        brush.shape = BrushShape::Round;
+    }
+    if is_square {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Square").clicked {
+        brush.shape = BrushShape::Square;
+    }
+    if is_stamp {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Stamp").clicked {
+        match Stamp::from_path(&state.stamp_path) {
+            Ok(stamp) => brush.shape = BrushShape::Stamp(stamp),
+            Err(_) => state.error_text = "Failed to load brush stamp.".into(),
+        }
+    }
+    ui.pop_layout();
+
+    let stamp_path = ui.text_box("Stamp path");
+    if stamp_path.text_edited {
+        state.stamp_path = stamp_path.text;
+    }
+
+    ui.push_layout("Brush size row", Layout::ToolRow);
+    if ui.button("-##size").clicked && brush.size > 1 {
+        brush.size -= 1;
+    }
+    ui.label(&format!("Size: {}###size", brush.size));
+    if ui.button("+##size").clicked && brush.size < 256 {
+        brush.size += 1;
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Brush hardness row", Layout::ToolRow);
+    if ui.button("-##hardness").clicked {
+        brush.hardness = (brush.hardness - 0.1).max(0.0);
+    }
+    ui.label(&format!("Hardness: {:.0}%###hardness", brush.hardness * 100.0));
+    if ui.button("+##hardness").clicked {
+        brush.hardness = (brush.hardness + 0.1).min(1.0);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Brush spacing row", Layout::ToolRow);
+    if ui.button("-##spacing").clicked {
+        brush.spacing = (brush.spacing - 0.05).max(0.05);
+    }
+    ui.label(&format!("Spacing: {:.0}%###spacing", brush.spacing * 100.0));
+    if ui.button("+##spacing").clicked {
+        brush.spacing = (brush.spacing + 0.05).min(2.0);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Brush opacity row", Layout::ToolRow);
+    if ui.button("-##opacity").clicked {
+        brush.opacity = (brush.opacity - 0.1).max(0.0);
+    }
+    ui.label(&format!("Opacity: {:.0}%###opacity", brush.opacity * 100.0));
+    if ui.button("+##opacity").clicked {
+        brush.opacity = (brush.opacity + 0.1).min(1.0);
+    }
+    ui.pop_layout();
+
+    if ui.button("Capture Brush from Selection").clicked {
+        let captured = state.layer_selection().and_then(|rect| Stamp::from_layer(state.active_layer(), rect));
+        match captured {
+            Some(stamp) => {
+                if state.brush_library.add(stamp.clone()).is_err() {
+                    state.error_text = "Failed to save brush to library.".into();
+                }
+                state.brush.shape = BrushShape::Stamp(stamp);
+            }
+            None => state.error_text = "Select a region to capture a brush.".into(),
+        }
+    }
+    if state.brush.recolor {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Recolor to Foreground").clicked {
+        state.brush.recolor = !state.brush.recolor;
+    }
+
+    for (name, stamp) in &state.brush_library.brushes {
+        if ui.button(name).clicked {
+            state.brush.shape = BrushShape::Stamp(stamp.clone());
+        }
+    }
+}
+
+fn draw_spray_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Spray Settings", rect!(50, 400, 250, 300));
+    ui.push_layout("Spray columns", Layout::ToolColumn);
+
+    let spray_can = &mut state.spray_can;
+
+    ui.push_layout("Spray distribution row", Layout::ToolRow);
+    for (name, distribution) in &[("Uniform", SprayDistribution::Uniform), ("Gaussian", SprayDistribution::Gaussian)] {
+        if spray_can.distribution == *distribution {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            spray_can.distribution = *distribution;
+        }
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Spray radius row", Layout::ToolRow);
+    if ui.button("-##radius").clicked {
+        spray_can.radius = (spray_can.radius - 5.0).max(5.0);
+    }
+    ui.label(&format!("Radius: {}###radius", spray_can.radius));
+    if ui.button("+##radius").clicked {
+        spray_can.radius = (spray_can.radius + 5.0).min(200.0);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Spray density row", Layout::ToolRow);
+    if ui.button("-##density").clicked {
+        spray_can.density = (spray_can.density - 0.01).max(0.01);
+    }
+    ui.label(&format!("Density: {:.0}%###density", spray_can.density * 100.0));
+    if ui.button("+##density").clicked {
+        spray_can.density = (spray_can.density + 0.01).min(1.0);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Spray flow row", Layout::ToolRow);
+    if ui.button("-##flow").clicked {
+        spray_can.flow = (spray_can.flow - 5.0).max(5.0);
+    }
+    ui.label(&format!("Flow: {}/s###flow", spray_can.flow));
+    if ui.button("+##flow").clicked {
+        spray_can.flow = (spray_can.flow + 5.0).min(120.0);
+    }
+    ui.pop_layout();
+}
+
+fn draw_bucket_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Bucket Settings", rect!(50, 400, 250, 500));
+    ui.push_layout("Bucket columns", Layout::ToolColumn);
+
+    let options = &mut state.fill_options;
+
+    ui.push_layout("Bucket tolerance row", Layout::ToolRow);
+    if ui.button("-##tolerance").clicked {
+        options.tolerance = (options.tolerance - 0.05).max(0.0);
+    }
+    ui.label(&format!("Tolerance: {:.0}%###tolerance", options.tolerance * 100.0));
+    if ui.button("+##tolerance").clicked {
+        options.tolerance = (options.tolerance + 0.05).min(1.0);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Bucket distance row", Layout::ToolRow);
+    for (name, distance) in &[("Per Channel", ColorDistance::PerChannel), ("Perceptual", ColorDistance::Perceptual)] {
+        if options.distance == *distance {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            options.distance = *distance;
+        }
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Bucket connectivity row", Layout::ToolRow);
+    for (name, connectivity) in &[("4-Connected", Connectivity::Four), ("8-Connected", Connectivity::Eight)] {
+        if options.connectivity == *connectivity {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            options.connectivity = *connectivity;
+        }
+    }
+    ui.pop_layout();
+
+    if options.contiguous {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Contiguous").clicked {
+        options.contiguous = !options.contiguous;
+    }
+    if options.sample_merged {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Sample Merged").clicked {
+        options.sample_merged = !options.sample_merged;
+    }
+
+    if state.fill_pattern.is_none() {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Solid Color").clicked {
+        state.fill_pattern = None;
+    }
+    if ui.button("Pattern from Brush").clicked {
+        match &state.brush.shape {
+            BrushShape::Stamp(stamp) => state.fill_pattern = Some(stamp.clone()),
+            _ => state.error_text = "The current brush isn't a stamp.".into(),
+        }
+    }
+    if ui.button("Pattern from Selection").clicked {
+        match state.layer_selection().and_then(|rect| Stamp::from_layer(state.active_layer(), rect)) {
+            Some(stamp) => state.fill_pattern = Some(stamp),
+            None => state.error_text = "Select a region to use as a pattern.".into(),
+        }
+    }
+    let pattern_path = ui.text_box("Pattern path");
+    if pattern_path.text_edited {
+        state.pattern_path = pattern_path.text;
+    }
+    if ui.button("Pattern from File").clicked {
+        match Stamp::from_path(&state.pattern_path) {
+            Ok(stamp) => state.fill_pattern = Some(stamp),
+            Err(_) => state.error_text = "Failed to load pattern.".into(),
+        }
+    }
+
+    ui.push_layout("Bucket pattern align row", Layout::ToolRow);
+    for (name, align) in &[("Align to Canvas", PatternAlign::Canvas), ("Align to Click", PatternAlign::Click)] {
+        if state.pattern_align == *align {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.pattern_align = *align;
+        }
+    }
+    ui.pop_layout();
+}
+
+fn draw_gradient_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Gradient Settings", rect!(50, 400, 250, 300));
+    ui.push_layout("Gradient columns", Layout::ToolColumn);
+
+    ui.push_layout("Gradient shape row", Layout::ToolRow);
+    let shapes = [
+        ("Linear", GradientShape::Linear),
+        ("Radial", GradientShape::Radial),
+        ("Angular", GradientShape::Angular),
+        ("Diamond", GradientShape::Diamond),
+    ];
+    for (name, shape) in &shapes {
+        if state.gradient.shape == *shape {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.gradient.shape = *shape;
+        }
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Gradient colors row", Layout::ToolRow);
+    if !state.gradient_use_ramp {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Foreground to Background").clicked {
+        state.gradient_use_ramp = false;
+    }
+    if state.gradient_use_ramp {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Palette Ramp").clicked {
+        state.gradient_use_ramp = true;
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Gradient ramp row", Layout::ToolRow);
+    ui.label(&format!("Ramp: {} colors (ctrl-click swatches)###ramp", state.gradient_ramp.len()));
+    if ui.button("Clear Ramp").clicked {
+        state.gradient_ramp.clear();
+    }
+    ui.pop_layout();
+
+    if state.gradient.dither {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Dither").clicked {
+        state.gradient.dither = !state.gradient.dither;
+    }
+}
+
+fn draw_picker_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Picker Settings", rect!(50, 400, 250, 200));
+    ui.push_layout("Picker columns", Layout::ToolColumn);
+
+    let picker = &mut state.color_picker;
+    ui.push_layout("Picker source row", Layout::ToolRow);
+    for (name, source) in &[("Active Layer", SampleSource::ActiveLayer), ("Merged", SampleSource::Merged)] {
+        if picker.source == *source {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            picker.source = *source;
+        }
+    }
+    if let SampleSource::Layer(_) = picker.source {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Layer").clicked {
+        picker.source = SampleSource::Layer(state.active_layer_idx);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Picker layer row", Layout::ToolRow);
+    let layer = match picker.source {
+        SampleSource::Layer(i) => i,
+        _ => state.active_layer_idx,
+    };
+    if ui.button("-##layer").clicked {
+        picker.source = SampleSource::Layer(layer.saturating_sub(1));
+    }
+    ui.label(&format!("Layer: {}###layer", layer));
+    if ui.button("+##layer").clicked {
+        picker.source = SampleSource::Layer((layer + 1).min(state.image.layers.len() - 1));
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Picker radius row", Layout::ToolRow);
+    if ui.button("-##radius").clicked {
+        picker.radius = picker.radius.saturating_sub(1);
+    }
+    let size = picker.radius * 2 + 1;
+    ui.label(&format!("Average: {}x{}###radius", size, size));
+    if ui.button("+##radius").clicked {
+        picker.radius = (picker.radius + 1).min(10);
+    }
+    ui.pop_layout();
 
-    let colors = [
-        color!(0, 0, 0),
-        color!(70, 70, 70),
-        color!(120, 120, 120),
-        color!(153, 0, 48),
-        color!(237, 28, 36),
-        color!(255, 126, 0),
-        color!(255, 194, 14),
-        color!(255, 242, 0),
-        color!(168, 230, 29),
-        color!(34, 177, 76),
-        color!(0, 183, 239),
-        color!(77, 109, 243),
-        color!(47, 54, 153),
-        color!(111, 49, 152),
-        color!(255, 255, 255),
-        color!(220, 220, 220),
-        color!(180, 180, 180),
-        color!(156, 90, 60),
-        color!(255, 163, 177),
-        color!(229, 170, 122),
-        color!(145, 228, 156),
-        color!(255, 249, 189),
-        color!(211, 249, 188),
-        color!(157, 187, 97),
-        color!(153, 217, 234),
-        color!(112, 154, 209),
-        color!(84, 109, 142),
-        color!(181, 165, 213),
+    ui.label("Alt-click picks with any tool.");
+}
+
+/// Draws a zoomed view of the pixels around the cursor, with the sampled
+/// color underneath.
+fn draw_loupe(state: &mut State, mouse: Vec2) {
+    const CELLS: i32 = 11;
+    const CELL_SIZE: f32 = 8.0;
+    let (x, y) = state.screen_to_canvas(mouse);
+    let layer_rect = state.active_layer().rect;
+    let (x, y) = (x + layer_rect.x, y + layer_rect.y);
+    let picker = state.color_picker;
+    let origin = vec2!(mouse.x + 20.0, mouse.y + 20.0);
+    let size = CELLS as f32 * CELL_SIZE;
+
+    g::draw_rectangle(origin.x - 2.0, origin.y - 2.0, size + 4.0, size + 24.0, g::BLACK);
+    for cy in 0..CELLS {
+        for cx in 0..CELLS {
+            let (px, py) = (x + cx - CELLS / 2, y + cy - CELLS / 2);
+            // Outside the image and transparent areas show as gray
+            let color = picker.pixel(&state.image, state.active_layer_idx, px, py)
+                .map(|c| layer::composite(color!(128, 128, 128), c, 1.0))
+                .unwrap_or(color!(128, 128, 128));
+            g::draw_rectangle(origin.x + cx as f32 * CELL_SIZE, origin.y + cy as f32 * CELL_SIZE, CELL_SIZE, CELL_SIZE, color);
+        }
+    }
+    let center = (CELLS / 2) as f32 * CELL_SIZE;
+    g::draw_rectangle_lines(origin.x + center - 1.0, origin.y + center - 1.0, CELL_SIZE + 2.0, CELL_SIZE + 2.0, 2.0, g::WHITE);
+    if let Some(color) = picker.sample(&state.image, state.active_layer_idx, x, y) {
+        g::draw_rectangle(origin.x, origin.y + size + 2.0, size, 18.0, color);
+    }
+}
+
+fn draw_replace_settings(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Replace Settings", rect!(50, 400, 250, 200));
+    ui.push_layout("Replace columns", Layout::ToolColumn);
+
+    ui.push_layout("Replace tolerance row", Layout::ToolRow);
+    if ui.button("-##tolerance").clicked {
+        state.replace_tolerance = (state.replace_tolerance - 0.01).max(0.0);
+    }
+    ui.label(&format!("Tolerance: {:.0}%###tolerance", state.replace_tolerance * 100.0));
+    if ui.button("+##tolerance").clicked {
+        state.replace_tolerance = (state.replace_tolerance + 0.01).min(1.0);
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Replace distance row", Layout::ToolRow);
+    for (name, distance) in &[("Per Channel", ColorDistance::PerChannel), ("Perceptual", ColorDistance::Perceptual)] {
+        if state.replace_distance == *distance {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.replace_distance = *distance;
+        }
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Replace scope row", Layout::ToolRow);
+    let scopes = [
+        ("Layer", ReplaceScope::Layer),
+        ("Selection", ReplaceScope::Selection),
+        ("All Layers", ReplaceScope::AllLayers),
     ];
+    for (name, scope) in &scopes {
+        if state.replace_scope == *scope {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.replace_scope = *scope;
+        }
+    }
+    ui.pop_layout();
+
+    if ui.button("Replace Background with Foreground").clicked {
+        let (from, to) = (state.background_color, state.foreground_color);
+        state.replace_color(from, to);
+    }
+}
+
+fn draw_color_selector(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Color Selector", rect!(200, 50, 100, 520));
+    ui.push_layout("Color columns", Layout::ToolColumn);
 
-    for color in &colors {
+    for (i, color) in state.palette.colors.iter().enumerate() {
         temp_style!(ui, background_color: *color);
-        if state.active_color == *color {
+        if state.palette_selected == Some(i) {
             temp_style!(ui, border_color: color!(255, 255, 0));
         }
-        let hash = format!("##{:?}", color);
-        if ui.button(&hash).clicked {
-            state.active_color = *color;
+        if ui.button(&format!("##swatch {}", i)).clicked {
+            if g::is_ctrl_down() {
+                state.gradient_ramp.push(*color);
+            } else {
+                state.foreground_color = *color;
+                state.palette_selected = Some(i);
+            }
+        }
+    }
+
+    ui.push_layout("Palette edit row", Layout::ToolRow);
+    // In indexed mode the image's palette is edited, so its pixels follow,
+    // and copied back to the selector below
+    let indexed = state.image.is_indexed();
+    if ui.button("+##swatch").clicked {
+        if !indexed {
+            state.palette.add(state.foreground_color);
+            state.palette_selected = Some(state.palette.colors.len() - 1);
+        } else if state.image.add_palette_color(state.foreground_color) {
+            state.palette_selected = Some(state.palette.colors.len());
+        } else {
+            state.error_text = format!("Indexed palettes can't have more than {} colors.", MAX_INDEXED_COLORS);
+        }
+    }
+    // The buttons stay put with nothing selected, since widgets that come
+    // and go are left behind in the layout
+    let selected = state.palette_selected.filter(|&i| i < state.palette.colors.len());
+    if ui.button("-##swatch").clicked {
+        if let Some(i) = selected {
+            if indexed {
+                state.image.remove_palette_color(i);
+            } else {
+                state.palette.remove(i);
+            }
+            state.palette_selected = None;
+        }
+    }
+    if ui.button("Set").clicked {
+        if let Some(i) = selected {
+            if indexed {
+                state.image.set_palette_color(i, state.foreground_color);
+            } else {
+                state.palette.set(i, state.foreground_color);
+            }
+        }
+    }
+    if ui.button("<").clicked {
+        if let Some(i) = selected.filter(|&i| i > 0) {
+            if indexed {
+                state.image.move_palette_color(i, i - 1);
+            } else {
+                state.palette.move_color(i, i - 1);
+            }
+            state.palette_selected = Some(i - 1);
+        }
+    }
+    if ui.button(">").clicked {
+        if let Some(i) = selected.filter(|&i| i + 1 < state.palette.colors.len()) {
+            if indexed {
+                state.image.move_palette_color(i, i + 1);
+            } else {
+                state.palette.move_color(i, i + 1);
+            }
+            state.palette_selected = Some(i + 1);
+        }
+    }
+    ui.pop_layout();
+
+    let palette_path = ui.text_box("Palette path");
+    if palette_path.text_edited {
+        state.palette_path = palette_path.text;
+    }
+    ui.push_layout("Palette file row", Layout::ToolRow);
+    if ui.button("Load").clicked {
+        match Palette::load(Path::new(&state.palette_path)) {
+            Ok(palette) if indexed => {
+                if let Err(e) = state.image.convert_to_indexed(palette, state.quantize_dither) {
+                    state.error_text = format!("Failed to use palette: {}", e);
+                }
+                state.palette_selected = None;
+            }
+            Ok(palette) => {
+                state.palette = palette;
+                state.palette_selected = None;
+            }
+            Err(e) => state.error_text = format!("Failed to load palette: {}", e),
+        }
+    }
+    if ui.button("Save##palette").clicked {
+        if let Err(e) = state.palette.save(Path::new(&state.palette_path)) {
+            state.error_text = format!("Failed to save palette: {}", e);
+        }
+    }
+    ui.pop_layout();
+
+    // Indexed images are always locked to their palette
+    if state.palette_lock || state.image.is_indexed() {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Lock to Palette").clicked {
+        state.palette_lock = !state.palette_lock;
+    }
+
+    if let Some(palette) = &state.image.palette {
+        if *palette != state.palette {
+            state.palette = palette.clone();
         }
     }
 }
 
+/// The most swatches the color selector will show.
+const MAX_PALETTE_COLORS: usize = 256;
+
+fn draw_quantize_window(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Quantize", rect!(320, 480, 260, 170));
+    ui.push_layout("Quantize columns", Layout::ToolColumn);
+
+    if ui.button("Extract Palette from Image").clicked {
+        let palette = quantize::extract_palette(&state.image);
+        if palette.colors.len() > MAX_PALETTE_COLORS {
+            state.error_text = format!("The image has {} colors. Quantize it to {} or fewer first.",
+                palette.colors.len(), MAX_PALETTE_COLORS);
+        } else {
+            state.palette = palette;
+            state.palette_selected = None;
+        }
+    }
+
+    ui.push_layout("Quantize method row", Layout::ToolRow);
+    let methods = [
+        ("Median Cut", QuantizeMethod::MedianCut),
+        ("K-Means", QuantizeMethod::KMeans),
+        ("Octree", QuantizeMethod::Octree),
+    ];
+    for (name, method) in &methods {
+        if state.quantize_method == *method {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.quantize_method = *method;
+        }
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Quantize dither row", Layout::ToolRow);
+    let dithers = [
+        ("No Dither", Dither::None),
+        ("Floyd-Steinberg", Dither::FloydSteinberg),
+        ("Ordered", Dither::Ordered),
+    ];
+    for (name, dither) in &dithers {
+        if state.quantize_dither == *dither {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.quantize_dither = *dither;
+        }
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Quantize colors row", Layout::ToolRow);
+    if ui.button("-##colors").clicked {
+        state.quantize_colors = (state.quantize_colors - 1).max(2);
+    }
+    ui.label(&format!("Colors: {}###colors", state.quantize_colors));
+    if ui.button("+##colors").clicked {
+        state.quantize_colors = (state.quantize_colors + 1).min(MAX_PALETTE_COLORS);
+    }
+    ui.pop_layout();
+
+    if ui.button("Quantize").clicked {
+        state.palette = quantize::quantize(&mut state.image, state.quantize_colors, state.quantize_method, state.quantize_dither);
+        state.palette_selected = None;
+        if state.image.is_indexed() {
+            // Already remapped, so this only rebuilds the indices
+            if let Err(e) = state.image.convert_to_indexed(state.palette.clone(), Dither::None) {
+                state.error_text = format!("Failed to use palette: {}", e);
+            }
+        }
+    }
+}
+
+fn draw_color_mode_window(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Color Mode", rect!(600, 50, 260, 160));
+    ui.push_layout("Color mode columns", Layout::ToolColumn);
+
+    ui.push_layout("Color mode row", Layout::ToolRow);
+    let indexed = state.image.is_indexed();
+    if !indexed {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("RGBA").clicked && indexed {
+        state.image.convert_to_rgba();
+    }
+    if indexed {
+        temp_style!(ui, background_color: color!(255, 255, 0));
+    }
+    if ui.button("Indexed").clicked && !indexed {
+        // Uses the quantize window's dither setting
+        if let Err(e) = state.image.convert_to_indexed(state.palette.clone(), state.quantize_dither) {
+            state.error_text = format!("Failed to convert to indexed: {}", e);
+        }
+        state.palette_selected = None;
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Bit depth row", Layout::ToolRow);
+    for (name, depth) in &[("8-bit", BitDepth::Eight), ("16-bit", BitDepth::Sixteen)] {
+        if state.image.depth == *depth {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.image.set_bit_depth(*depth);
+        }
+    }
+    ui.pop_layout();
+
+    let export_path = ui.text_box("Indexed export path");
+    if export_path.text_edited {
+        state.export_path = export_path.text;
+    }
+    if ui.button("Export Indexed PNG/GIF").clicked {
+        if let Err(e) = state.image.save_indexed(Path::new(&state.export_path)) {
+            state.error_text = format!("Failed to export: {}", e);
+        }
+    }
+}
+
+fn draw_color_picker(ui: &mut Ui, state: &mut State) {
+    ui.push_window("Color Picker", rect!(320, 50, 260, 420));
+    ui.push_layout("Picker columns", Layout::ToolColumn);
+
+    ui.push_layout("Picker target row", Layout::ToolRow);
+    for (name, background) in &[("Foreground", false), ("Background", true)] {
+        if state.picker_edits_background == *background {
+            temp_style!(ui, background_color: color!(255, 255, 0));
+        }
+        if ui.button(name).clicked {
+            state.picker_edits_background = *background;
+        }
+    }
+    ui.pop_layout();
+
+    let mut color = if state.picker_edits_background {
+        state.background_color
+    } else {
+        state.foreground_color
+    };
+    // Only take the hue and saturation from the color when it was changed
+    // somewhere else, since they're lost at zero saturation or value
+    if color != state.picker_color {
+        state.picker_hsv = util::rgb_to_hsv(color);
+    }
+    let (mut hue, mut saturation, mut value) = state.picker_hsv;
+    let mut alpha = color.a;
+    let mut hsv_changed = false;
+    let mut rgb_changed = false;
+
+    let pure_hue = util::hsv_to_rgb(hue, 1.0, 1.0, 1.0);
+    let sv = ui.color_area("Saturation/value", 240.0, 160.0,
+        WidgetFill::SaturationValue(pure_hue), Some(vec2!(saturation, 1.0 - value)));
+    if sv.dragging {
+        saturation = sv.drag_pos.x;
+        value = 1.0 - sv.drag_pos.y;
+        hsv_changed = true;
+    }
+
+    let hues = (0..=6).map(|i| util::hsv_to_rgb(i as f32 * 60.0, 1.0, 1.0, 1.0)).collect();
+    let hue_bar = ui.color_area("Hue", 240.0, 16.0,
+        WidgetFill::HorizontalGradient(hues), Some(vec2!(hue / 360.0, 0.5)));
+    if hue_bar.dragging {
+        hue = hue_bar.drag_pos.x * 360.0;
+        hsv_changed = true;
+    }
+
+    let opaque = Color::new(color.r, color.g, color.b, 1.0);
+    let transparent = Color::new(color.r, color.g, color.b, 0.0);
+    let alpha_bar = ui.color_area("Alpha", 240.0, 16.0,
+        WidgetFill::HorizontalGradient(vec![transparent, opaque]), Some(vec2!(alpha, 0.5)));
+    if alpha_bar.dragging {
+        alpha = alpha_bar.drag_pos.x;
+    }
+
+    ui.push_layout("Picker RGB row", Layout::ToolRow);
+    let [mut r, mut g, mut b, mut a] = util::color_to_rgba8(color).map(|c| c as f32);
+    ui.label("R");
+    rgb_changed |= ui.number_box("##r", &mut r, 0.0, 255.0);
+    ui.label("G");
+    rgb_changed |= ui.number_box("##g", &mut g, 0.0, 255.0);
+    ui.label("B");
+    rgb_changed |= ui.number_box("##b", &mut b, 0.0, 255.0);
+    ui.pop_layout();
+
+    ui.push_layout("Picker HSV row", Layout::ToolRow);
+    let (mut h, mut s, mut v) = (hue, saturation * 100.0, value * 100.0);
+    ui.label("H");
+    if ui.number_box("##h", &mut h, 0.0, 360.0) {
+        hue = h;
+        hsv_changed = true;
+    }
+    ui.label("S");
+    if ui.number_box("##s", &mut s, 0.0, 100.0) {
+        saturation = s / 100.0;
+        hsv_changed = true;
+    }
+    ui.label("V");
+    if ui.number_box("##v", &mut v, 0.0, 100.0) {
+        value = v / 100.0;
+        hsv_changed = true;
+    }
+    ui.pop_layout();
+
+    ui.push_layout("Picker alpha/hex row", Layout::ToolRow);
+    ui.label("A");
+    if ui.number_box("##a", &mut a, 0.0, 255.0) {
+        alpha = a / 255.0;
+    }
+    ui.label("#");
+    let mut hex = util::color_to_hex(color);
+    if ui.edit_box("##hex", 100.0, &mut hex) {
+        // Partially typed colors are ignored until they parse
+        if let Ok(parsed) = util::color_from_hex_str(&hex) {
+            color = parsed;
+            alpha = parsed.a;
+            state.picker_hsv = util::rgb_to_hsv(color);
+        }
+    }
+    ui.pop_layout();
+
+    if hsv_changed {
+        state.picker_hsv = (hue, saturation, value);
+        color = util::hsv_to_rgb(hue, saturation, value, alpha);
+    } else if rgb_changed {
+        let rgb = Color::from_rgba(r as u8, g as u8, b as u8, 255);
+        color = Color::new(rgb.r, rgb.g, rgb.b, alpha);
+        state.picker_hsv = util::rgb_to_hsv(color);
+    } else {
+        color.a = alpha;
+    }
+
+    if state.picker_edits_background {
+        state.background_color = color;
+    } else {
+        state.foreground_color = color;
+    }
+    state.picker_color = color;
+}
+
 #[macroquad::main("Pixel Editor")]
 async fn main() {
 //     g::simulate_mouse_with_touch(false);
@@ -167,7 +1095,8 @@ async fn main() {
     state.active_layer_idx = 3;
     state.active_layer().fill(0, 0, color!(0, 0, 255));
 
-    let mut texture = g::Texture2D::from_rgba8(state.image.rect.w as u16, state.image.rect.h as u16, &state.image.raw_data());
+    let mut texture = TiledTexture::new(state.image.rect.w, state.image.rect.h);
+    let mut composite = CompositeCache::new(&state.image, state.active_layer_idx);
     let mut click_intercepted = false;
 
     loop {
@@ -232,43 +1161,82 @@ async fn main() {
 
         draw_tool_pane(&mut ui, &mut state);
         draw_color_selector(&mut ui, &mut state);
+        draw_color_picker(&mut ui, &mut state);
+        draw_quantize_window(&mut ui, &mut state);
+        draw_color_mode_window(&mut ui, &mut state);
+        match state.active_tool.as_str() {
+            "Pencil" => draw_pencil_settings(&mut ui, &mut state),
+            "Paintbrush" => draw_brush_settings(&mut ui, &mut state),
+            "Spray Can" => draw_spray_settings(&mut ui, &mut state),
+            "Paint Bucket" => draw_bucket_settings(&mut ui, &mut state),
+            "Gradient" => draw_gradient_settings(&mut ui, &mut state),
+            "Color Replace" => draw_replace_settings(&mut ui, &mut state),
+            "Color Picker" => draw_picker_settings(&mut ui, &mut state),
+            _ => {}
+        }
 
         //////////////
 
         g::clear_background(color!(50, 50, 50));
         let rect = rect!(0, 0, state.image.rect.w, state.image.rect.h);
-        let src_rect = rect;
         let dest_rect = rect!(
             state.canvas.x - (rect.w * state.canvas_scale as f32 / 2.0).round(),
             state.canvas.y - (rect.h * state.canvas_scale as f32 / 2.0).round(),
             rect.w * state.canvas_scale as f32,
             rect.h * state.canvas_scale as f32,
         );
-        // TODO Texture2D only supports u16, determine if we need to find an
-        // alternative or go with it and do bounds checking
-        let dirty_rect = state.image.dirty_rect();
-        // let dirty_rect = state.image.rect;
-        let dirty_data = state.image.partial_data(dirty_rect);
-        let dirty_image = g::Image {
-            bytes: dirty_data,
-            width: dirty_rect.w as u16,
-            height: dirty_rect.h as u16,
-        };
-        if dirty_rect.w != 0 && dirty_rect.h != 0 {
-            texture.update_part(&dirty_image, dirty_rect.x, dirty_rect.y, dirty_rect.w as i32, dirty_rect.h as i32);
+        if let Some(snapshot) = &state.stroke_snapshot {
+            let layer = &mut state.image.layers[state.active_layer_idx];
+            let dirty = layer.dirty_rect;
+            quantize::snap_changed(layer, &state.palette, snapshot, dirty);
+            if !state.currently_drawing {
+                state.stroke_snapshot = None;
+            }
         }
+        texture.invalidate(composite.update(&state.image, state.active_layer_idx));
         state.image.clear_dirty();
 
-        texture.set_filter(g::FilterMode::Nearest);
-        g::draw_texture_ex(&texture, dest_rect.x, dest_rect.y, g::WHITE, g::DrawTextureParams {
-            dest_size: Some(vec2!(dest_rect.w, dest_rect.h)),
-            source: Some(src_rect),
-            ..Default::default()
-        });
+        // The part of the image on screen
+        let viewport = ImageRect::new(
+            (-dest_rect.x / state.canvas_scale).floor() as i32,
+            (-dest_rect.y / state.canvas_scale).floor() as i32,
+            (g::screen_width() / state.canvas_scale).ceil() as u32 + 1,
+            (g::screen_height() / state.canvas_scale).ceil() as u32 + 1,
+        );
+        texture.update(&composite, viewport);
+        texture.draw(dest_rect.x, dest_rect.y, state.canvas_scale, viewport);
+
+        if let Some(selection) = state.selection {
+            g::draw_rectangle_lines(
+                dest_rect.x + selection.x as f32 * state.canvas_scale,
+                dest_rect.y + selection.y as f32 * state.canvas_scale,
+                selection.w as f32 * state.canvas_scale,
+                selection.h as f32 * state.canvas_scale,
+                2.0,
+                color!(0, 120, 255),
+            );
+        }
+
+        if state.currently_drawing && state.active_tool == "Gradient" && !state.picking {
+            let layer_rect = state.active_layer().rect;
+            let to_screen = |(x, y): (i32, i32)| vec2!(
+                dest_rect.x + ((x + layer_rect.x) as f32 + 0.5) * state.canvas_scale,
+                dest_rect.y + ((y + layer_rect.y) as f32 + 0.5) * state.canvas_scale,
+            );
+            let (start, end) = (to_screen(state.stroke_start), to_screen(state.stroke_end));
+            g::draw_line(start.x, start.y, end.x, end.y, 2.0, color!(0, 120, 255));
+        }
+
+        let picking = state.active_tool == "Color Picker" || g::is_alt_down() || (state.currently_drawing && state.picking);
+        if picking && !ui.mouse_intercepted {
+            let (mouse_x, mouse_y) = g::mouse_position();
+            draw_loupe(&mut state, vec2!(mouse_x, mouse_y));
+        }
 
         //////////////
 
         g::draw_text("Hello", 100.0, 100.0, 30.0, g::DARKGRAY);
+        g::draw_text(&state.error_text, 5.0, g::screen_height() - 30.0, 20.0, color!(255, 0, 0));
 
         if !has_updated || g::is_key_pressed(Key::Space) {
             has_updated = true;
@@ -310,7 +1278,23 @@ async fn main() {
             state.canvas_offset_baseline.y = mouse_y;
         }
 
-        if !g::is_mouse_left_down() {
+        if g::is_key_pressed(Key::X) && !ui.keyboard_intercepted {
+            state.swap_colors();
+        }
+
+        if !g::is_mouse_left_down() && !g::is_mouse_right_down() {
+            if state.currently_drawing && state.active_tool == "Gradient" && !state.picking {
+                let stops = if state.gradient_use_ramp {
+                    state.gradient_ramp.clone()
+                } else if state.drawing_with_background {
+                    vec![state.background_color, state.foreground_color]
+                } else {
+                    vec![state.foreground_color, state.background_color]
+                };
+                let clip = state.layer_selection();
+                let (gradient, start, end) = (state.gradient, state.stroke_start, state.stroke_end);
+                gradient.draw(state.active_layer(), start, end, &stops, clip);
+            }
             state.currently_drawing = false;
         }
 
@@ -327,41 +1311,71 @@ async fn main() {
             state.canvas_scale *= (10.0 + wheel_y) / 10.0;
             state.update_canvas_position();
         }
-        if (g::is_mouse_left_down() && !ui.mouse_intercepted) || state.currently_drawing {
-            state.currently_drawing = true;
-            let color = state.active_color;
-
+        if ((g::is_mouse_left_down() || g::is_mouse_right_down()) && !ui.mouse_intercepted) || state.currently_drawing {
             let (mouse_x, mouse_y) = g::mouse_position();
             let (x, y) = state.screen_to_canvas(vec2!(mouse_x, mouse_y));
+            let stroke_started = !state.currently_drawing;
+            if stroke_started {
+                if state.palette_lock && !state.image.is_indexed() {
+                    state.stroke_snapshot = Some(state.active_layer().data.clone());
+                }
+                state.stroke.clear();
+                state.brush_stroke.begin(&state.brush);
+                state.spray_can.begin();
+                state.stroke_start = (x, y);
+                state.drawing_with_background = !g::is_mouse_left_down();
+                state.picking = g::is_alt_down();
+            }
+            state.stroke_end = (x, y);
+            state.currently_drawing = true;
+            let color = state.stroke_color();
+
             let (old_x, old_y) = state.screen_to_canvas(state.mouse_old);
 
             match state.active_tool.as_str() {
-                "Pencil" => state.active_layer().draw_line(old_x, old_y, x, y, color),
+                _ if state.picking => state.pick_color(x, y),
+                "Select" => {
+                    let (start_x, start_y) = state.stroke_start;
+                    let layer_rect = state.active_layer().rect;
+                    let selection = ImageRect::new(
+                        start_x.min(x) + layer_rect.x,
+                        start_y.min(y) + layer_rect.y,
+                        (x - start_x).unsigned_abs() + 1,
+                        (y - start_y).unsigned_abs() + 1,
+                    );
+                    state.selection = Some(selection.intersection(state.image.rect));
+                }
+                "Pencil" => {
+                    let layer = &mut state.image.layers[state.active_layer_idx];
+                    state.stroke.draw_line(layer, old_x, old_y, x, y, color);
+                }
                 "Paintbrush" => {
-                    for dx in -10..=10 {
-                        for dy in -10..=10 {
-                            if (dx as f64 * dx as f64 + dy as f64 * dy as f64).sqrt() < 10.0 {
-                                state.active_layer().draw_line(old_x + dx, old_y + dy, x + dx, y + dy, color);
-                            }
-                        }
-                    }
+                    let layer = &mut state.image.layers[state.active_layer_idx];
+                    state.brush_stroke.draw_line(layer, old_x, old_y, x, y, color);
                 }
-                "Color Picker" => {
-                    if let Some(color) = state.active_layer().get_pixel(x, y) {
-                        state.active_color = color;
+                "Color Picker" => state.pick_color(x, y),
+                "Paint Bucket" if stroke_started => {
+                    let options = state.fill_options;
+                    let sample = if options.sample_merged {
+                        Some(state.image.flatten().crop(state.active_layer().rect))
+                    } else {
+                        None
+                    };
+                    match &state.fill_pattern {
+                        Some(pattern) => {
+                            let layer = &mut state.image.layers[state.active_layer_idx];
+                            layer.fill_pattern(x, y, pattern, state.pattern_align, &options, sample.as_ref());
+                        }
+                        None => state.active_layer().fill_with(x, y, color, &options, sample.as_ref()),
                     }
                 }
-                "Paint Bucket" => {
-                    state.active_layer().fill(x, y, color);
-                }
                 "Spray Can" => {
-                    for _ in 0..100 {
-                        let dx = macroquad::rand::rand() as i32 % 100 - 50;
-                        let dy = macroquad::rand::rand() as i32 % 100 - 50;
-                        if (dx as f32 * dx as f32 + dy as f32 * dy as f32).sqrt() < 50.0 {
-                            state.active_layer().draw_pixel(x + dx, y + dy, color);
-                        }
-                        state.active_layer().add_dirty_rect(ImageRect::new(x - 51, y - 51, 102, 102));
+                    let layer = &mut state.image.layers[state.active_layer_idx];
+                    state.spray_can.spray(layer, x, y, color, g::get_frame_time());
+                }
+                "Color Replace" if stroke_started => {
+                    if let Some(from) = state.active_layer().get_pixel(x, y) {
+                        state.replace_color(from, color);
                     }
                 }
                 _ => {}
@@ -0,0 +1,388 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::app::Color;
+use super::util::{self, color_to_rgba8};
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    /// The file extension isn't one of the supported formats.
+    UnknownFormat,
+    /// The file doesn't follow its format, with a description of where.
+    Invalid(String),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(e) => write!(f, "{}", e),
+            PaletteError::UnknownFormat => write!(f, "unknown palette format"),
+            PaletteError::Invalid(reason) => write!(f, "invalid palette: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(e: io::Error) -> Self {
+        PaletteError::Io(e)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PaletteFormat {
+    /// GIMP .gpl
+    Gimp,
+    /// Lospec .hex, one RRGGBB color per line
+    Hex,
+    /// JASC .pal, from Paint Shop Pro
+    Jasc,
+    /// Photoshop .aco swatches
+    Aco,
+}
+
+impl PaletteFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gpl" => Some(PaletteFormat::Gimp),
+            "hex" => Some(PaletteFormat::Hex),
+            "pal" => Some(PaletteFormat::Jasc),
+            "aco" => Some(PaletteFormat::Aco),
+            _ => None,
+        }
+    }
+}
+
+/// An ordered list of swatches. None of the file formats store alpha, so
+/// swatches are saved opaque.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Palette {
+    pub name: String,
+    pub colors: Vec<Color>,
+}
+
+impl Palette {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            colors: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, color: Color) {
+        self.colors.push(color);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Color> {
+        if index < self.colors.len() {
+            Some(self.colors.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Moves the swatch at `from` so it ends up at `to`, shifting the swatches
+    /// in between.
+    pub fn move_color(&mut self, from: usize, to: usize) {
+        if from < self.colors.len() && to < self.colors.len() {
+            let color = self.colors.remove(from);
+            self.colors.insert(to, color);
+        }
+    }
+
+    pub fn set(&mut self, index: usize, color: Color) {
+        if let Some(swatch) = self.colors.get_mut(index) {
+            *swatch = color;
+        }
+    }
+
+    /// Loads a palette, picking the format from the file extension. The name
+    /// comes from the file if the format stores one, or the file stem if not.
+    pub fn load(path: &Path) -> Result<Self, PaletteError> {
+        let format = PaletteFormat::from_path(path).ok_or(PaletteError::UnknownFormat)?;
+        let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("Untitled");
+        let mut palette = match format {
+            PaletteFormat::Aco => Self::from_aco(name, &fs::read(path)?)?,
+            _ => {
+                let text = fs::read_to_string(path)?;
+                match format {
+                    PaletteFormat::Gimp => Self::from_gpl(&text)?,
+                    PaletteFormat::Hex => Self::from_hex(&text)?,
+                    _ => Self::from_jasc(&text)?,
+                }
+            }
+        };
+        if palette.name.is_empty() {
+            palette.name = name.into();
+        }
+        Ok(palette)
+    }
+
+    /// Saves the palette, picking the format from the file extension.
+    pub fn save(&self, path: &Path) -> Result<(), PaletteError> {
+        let format = PaletteFormat::from_path(path).ok_or(PaletteError::UnknownFormat)?;
+        match format {
+            PaletteFormat::Gimp => fs::write(path, self.to_gpl())?,
+            PaletteFormat::Hex => fs::write(path, self.to_hex())?,
+            PaletteFormat::Jasc => fs::write(path, self.to_jasc())?,
+            PaletteFormat::Aco => fs::write(path, self.to_aco())?,
+        }
+        Ok(())
+    }
+
+    pub fn from_gpl(text: &str) -> Result<Self, PaletteError> {
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some("GIMP Palette") {
+            return Err(PaletteError::Invalid("missing \"GIMP Palette\" header".into()));
+        }
+        let mut palette = Self::new("");
+        for (i, line) in lines.enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("Columns:") {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("Name:") {
+                palette.name = name.trim().into();
+                continue;
+            }
+            let channels: Vec<u8> = line.split_whitespace().take(3).map(|v| v.parse::<u8>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| PaletteError::Invalid(format!("bad color on line {}", i + 2)))?;
+            if channels.len() < 3 {
+                return Err(PaletteError::Invalid(format!("bad color on line {}", i + 2)));
+            }
+            palette.add(Color::from_rgba(channels[0], channels[1], channels[2], 255));
+        }
+        Ok(palette)
+    }
+
+    pub fn to_gpl(&self) -> String {
+        let mut text = format!("GIMP Palette\nName: {}\n#\n", self.name);
+        for color in &self.colors {
+            let [r, g, b, _] = color_to_rgba8(*color);
+            text.push_str(&format!("{:3} {:3} {:3}\t{}\n", r, g, b, util::color_to_hex(*color)));
+        }
+        text
+    }
+
+    pub fn from_hex(text: &str) -> Result<Self, PaletteError> {
+        let mut palette = Self::new("");
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let color = util::color_from_hex_str(line)
+                .map_err(|e| PaletteError::Invalid(format!("line {}: {}", i + 1, e)))?;
+            palette.add(Color::new(color.r, color.g, color.b, 1.0));
+        }
+        Ok(palette)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.colors.iter()
+            .map(|color| util::color_to_hex(Color::new(color.r, color.g, color.b, 1.0)).to_lowercase() + "\n")
+            .collect()
+    }
+
+    pub fn from_jasc(text: &str) -> Result<Self, PaletteError> {
+        let mut lines = text.lines().map(str::trim);
+        if lines.next() != Some("JASC-PAL") {
+            return Err(PaletteError::Invalid("missing \"JASC-PAL\" header".into()));
+        }
+        // Version, always 0100
+        lines.next();
+        let count: usize = lines.next().and_then(|l| l.parse().ok())
+            .ok_or_else(|| PaletteError::Invalid("missing color count".into()))?;
+        let mut palette = Self::new("");
+        for i in 0..count {
+            let channels: Option<Vec<u8>> = lines.next()
+                .map(|line| line.split_whitespace().map(|v| v.parse().ok()).collect())
+                .and_then(|channels: Option<Vec<u8>>| channels.filter(|c| c.len() >= 3));
+            let channels = channels.ok_or_else(|| PaletteError::Invalid(format!("bad color {}", i)))?;
+            palette.add(Color::from_rgba(channels[0], channels[1], channels[2], 255));
+        }
+        Ok(palette)
+    }
+
+    pub fn to_jasc(&self) -> String {
+        let mut text = format!("JASC-PAL\n0100\n{}\n", self.colors.len());
+        for color in &self.colors {
+            let [r, g, b, _] = color_to_rgba8(*color);
+            text.push_str(&format!("{} {} {}\n", r, g, b));
+        }
+        text
+    }
+
+    /// Reads version 1 swatches, or the version 2 section that follows them
+    /// if there is one, since only it has names. RGB, HSB and grayscale
+    /// swatches are supported.
+    pub fn from_aco(name: &str, data: &[u8]) -> Result<Self, PaletteError> {
+        let mut reader = AcoReader { data, pos: 0 };
+        let mut palette = Self::new(name);
+        let mut version = reader.u16()?;
+        loop {
+            if version != 1 && version != 2 {
+                return Err(PaletteError::Invalid(format!("unknown .aco version {}", version)));
+            }
+            let count = reader.u16()?;
+            palette.colors.clear();
+            for _ in 0..count {
+                let space = reader.u16()?;
+                let (w, x, y) = (reader.u16()?, reader.u16()?, reader.u16()?);
+                reader.u16()?;
+                let color = match space {
+                    0 => Color::from_rgba((w >> 8) as u8, (x >> 8) as u8, (y >> 8) as u8, 255),
+                    1 => util::hsv_to_rgb(w as f32 / 65536.0 * 360.0, x as f32 / 65535.0, y as f32 / 65535.0, 1.0),
+                    8 => {
+                        let v = 1.0 - w.min(10000) as f32 / 10000.0;
+                        Color::new(v, v, v, 1.0)
+                    }
+                    _ => return Err(PaletteError::Invalid(format!("unsupported color space {}", space))),
+                };
+                if version == 2 {
+                    reader.u16()?;
+                    let length = reader.u32()?;
+                    for _ in 0..length {
+                        reader.u16()?;
+                    }
+                }
+                palette.add(color);
+            }
+            if version == 2 || reader.pos == data.len() {
+                break;
+            }
+            version = reader.u16()?;
+        }
+        Ok(palette)
+    }
+
+    /// Writes both the version 1 and version 2 sections, so older readers
+    /// still see the colors. Swatches are named by their hex value.
+    pub fn to_aco(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for version in 1..=2u16 {
+            data.extend_from_slice(&version.to_be_bytes());
+            data.extend_from_slice(&(self.colors.len() as u16).to_be_bytes());
+            for color in &self.colors {
+                let [r, g, b, _] = color_to_rgba8(*color);
+                for value in [0, r as u16 * 257, g as u16 * 257, b as u16 * 257, 0] {
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+                if version == 2 {
+                    let name: Vec<u16> = util::color_to_hex(*color).encode_utf16().chain(Some(0)).collect();
+                    data.extend_from_slice(&0u16.to_be_bytes());
+                    data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                    for c in name {
+                        data.extend_from_slice(&c.to_be_bytes());
+                    }
+                }
+            }
+        }
+        data
+    }
+}
+
+/// The 28 colors the editor starts with.
+impl Default for Palette {
+    fn default() -> Self {
+        let colors = [
+            (0, 0, 0),
+            (70, 70, 70),
+            (120, 120, 120),
+            (153, 0, 48),
+            (237, 28, 36),
+            (255, 126, 0),
+            (255, 194, 14),
+            (255, 242, 0),
+            (168, 230, 29),
+            (34, 177, 76),
+            (0, 183, 239),
+            (77, 109, 243),
+            (47, 54, 153),
+            (111, 49, 152),
+            (255, 255, 255),
+            (220, 220, 220),
+            (180, 180, 180),
+            (156, 90, 60),
+            (255, 163, 177),
+            (229, 170, 122),
+            (145, 228, 156),
+            (255, 249, 189),
+            (211, 249, 188),
+            (157, 187, 97),
+            (153, 217, 234),
+            (112, 154, 209),
+            (84, 109, 142),
+            (181, 165, 213),
+        ];
+        Self {
+            name: "Default".into(),
+            colors: colors.iter().map(|&(r, g, b)| Color::from_rgba(r, g, b, 255)).collect(),
+        }
+    }
+}
+
+struct AcoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl AcoReader<'_> {
+    fn u16(&mut self) -> Result<u16, PaletteError> {
+        let bytes = self.data.get(self.pos..self.pos + 2)
+            .ok_or_else(|| PaletteError::Invalid("unexpected end of file".into()))?;
+        self.pos += 2;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, PaletteError> {
+        Ok((self.u16()? as u32) << 16 | self.u16()? as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Palette {
+        let mut palette = Palette {
+            name: "Sample".into(),
+            ..Palette::default()
+        };
+        palette.colors.truncate(6);
+        palette
+    }
+
+    #[test]
+    fn text_formats_round_trip() {
+        let palette = sample();
+        assert_eq!(Palette::from_gpl(&palette.to_gpl()).unwrap(), palette);
+        assert_eq!(Palette::from_hex(&palette.to_hex()).unwrap().colors, palette.colors);
+        assert_eq!(Palette::from_jasc(&palette.to_jasc()).unwrap().colors, palette.colors);
+    }
+
+    #[test]
+    fn aco_round_trips_and_reads_version_1_only() {
+        let palette = sample();
+        let data = palette.to_aco();
+        assert_eq!(Palette::from_aco("Sample", &data).unwrap(), palette);
+
+        let version_1_len = 4 + palette.colors.len() * 10;
+        assert_eq!(Palette::from_aco("Sample", &data[..version_1_len]).unwrap(), palette);
+        assert!(Palette::from_aco("Sample", &data[..version_1_len - 1]).is_err());
+    }
+
+    #[test]
+    fn reorders_swatches() {
+        let mut palette = sample();
+        let first = palette.colors[0];
+        palette.move_color(0, 3);
+        assert_eq!(palette.colors[3], first);
+        assert_eq!(palette.remove(3), Some(first));
+        assert_eq!(palette.colors.len(), 5);
+    }
+}
@@ -0,0 +1,121 @@
+use super::app::Color;
+use super::layer::{self, Image};
+
+/// Which pixels the Color Picker reads.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SampleSource {
+    ActiveLayer,
+    /// Every layer composited together, as shown on the canvas.
+    Merged,
+    Layer(usize),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ColorPicker {
+    pub source: SampleSource,
+    /// Average a square of (2 * radius + 1) pixels on a side around the
+    /// cursor rather than taking a single pixel.
+    pub radius: u32,
+}
+
+impl ColorPicker {
+    pub fn new() -> Self {
+        Self {
+            source: SampleSource::Merged,
+            radius: 0,
+        }
+    }
+
+    /// The color at (x, y) in image coordinates, or `None` if it's outside
+    /// the image or the sampled layer doesn't exist.
+    pub fn pixel(&self, image: &Image, active_layer: usize, x: i32, y: i32) -> Option<Color> {
+        if !image.rect.contains(x, y) {
+            return None;
+        }
+        let layer_at = |i: usize| {
+            let layer = image.layers.get(i)?;
+            // Outside the layer counts as transparent, not missing
+            Some(layer.get_pixel(x - layer.rect.x, y - layer.rect.y).unwrap_or(Color::new(0.0, 0.0, 0.0, 0.0)))
+        };
+        match self.source {
+            SampleSource::ActiveLayer => layer_at(active_layer),
+            SampleSource::Layer(i) => layer_at(i),
+            SampleSource::Merged => {
+                let mut color = Color::new(0.0, 0.0, 0.0, 0.0);
+                for i in 0..image.layers.len() {
+                    color = layer::composite(color, layer_at(i)?, 1.0);
+                }
+                Some(color)
+            }
+        }
+    }
+
+    /// The picked color at (x, y) in image coordinates, averaged over the
+    /// radius. Colors are weighted by alpha so transparent pixels don't darken
+    /// the result.
+    pub fn sample(&self, image: &Image, active_layer: usize, x: i32, y: i32) -> Option<Color> {
+        self.pixel(image, active_layer, x, y)?;
+        let r = self.radius as i32;
+        let (mut sum_r, mut sum_g, mut sum_b, mut sum_a, mut count) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for py in y - r..=y + r {
+            for px in x - r..=x + r {
+                if let Some(c) = self.pixel(image, active_layer, px, py) {
+                    sum_r += c.r * c.a;
+                    sum_g += c.g * c.a;
+                    sum_b += c.b * c.a;
+                    sum_a += c.a;
+                    count += 1.0;
+                }
+            }
+        }
+        if sum_a == 0.0 {
+            return Some(Color::new(0.0, 0.0, 0.0, 0.0));
+        }
+        Some(Color::new(sum_r / sum_a, sum_g / sum_a, sum_b / sum_a, sum_a / count))
+    }
+}
+
+impl Default for ColorPicker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app;
+    use super::super::layer::{Layer, ImageRect, TRANSPARENT};
+    use super::super::tiles::TiledPixels;
+
+    #[test]
+    fn samples_each_source() {
+        let mut image = Image::new(4, 4);
+        let mut top = Layer::new(ImageRect::new(2, 0, 2, 4));
+        top.data.fill(TRANSPARENT);
+        let red = Color::from_rgba(255, 0, 0, 255);
+        top.draw_pixel(0, 0, red);
+        image.layers.push(top);
+
+        let mut picker = ColorPicker::new();
+        assert_eq!(picker.sample(&image, 1, 2, 0), Some(red));
+        assert_eq!(picker.sample(&image, 1, 3, 0), Some(app::WHITE));
+        assert_eq!(picker.sample(&image, 1, 0, 0), Some(app::WHITE));
+
+        picker.source = SampleSource::ActiveLayer;
+        assert_eq!(picker.sample(&image, 1, 3, 0).unwrap().a, 0.0);
+        picker.source = SampleSource::Layer(0);
+        assert_eq!(picker.sample(&image, 1, 2, 0), Some(app::WHITE));
+        picker.source = SampleSource::Layer(5);
+        assert_eq!(picker.sample(&image, 1, 2, 0), None);
+    }
+
+    #[test]
+    fn averages_over_the_radius_ignoring_transparent_pixels() {
+        let mut image = Image::new(3, 1);
+        image.layers[0].data = TiledPixels::from_vec(3, 1, vec![[0, 0, 0, 255], TRANSPARENT, [255, 255, 255, 255]]);
+        let picker = ColorPicker { source: SampleSource::ActiveLayer, radius: 1 };
+        let color = picker.sample(&image, 0, 1, 0).unwrap();
+        assert_eq!((color.r, color.a), (0.5, 2.0 / 3.0));
+    }
+}
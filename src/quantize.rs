@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+
+use super::app::Color;
+use super::gradient::bayer_threshold;
+use super::layer::{Image, Layer, ImageRect};
+use super::palette::Palette;
+use super::tiles::TiledPixels;
+use super::util::color_to_rgba8;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum QuantizeMethod {
+    /// Repeatedly splits the box of colors with the widest channel range at
+    /// its median.
+    MedianCut,
+    /// Refines a median cut palette by moving each color to the mean of the
+    /// pixels closest to it.
+    KMeans,
+    /// Merges the least used branches of an RGB octree.
+    Octree,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Dither {
+    None,
+    /// Spreads each pixel's error to its unvisited neighbors.
+    FloydSteinberg,
+    /// Offsets each pixel by the 8x8 Bayer matrix threshold.
+    Ordered,
+}
+
+/// The distinct colors of an image and how many pixels use each, ignoring
+/// fully transparent pixels. Alpha is dropped, since palettes are opaque.
+pub type Histogram = HashMap<[u8; 3], u32>;
+
+pub fn histogram(layer: &Layer) -> Histogram {
+    let mut histogram = Histogram::new();
+    for &[r, g, b, a] in &layer.data {
+        if a != 0 {
+            *histogram.entry([r, g, b]).or_insert(0) += 1;
+        }
+    }
+    histogram
+}
+
+/// Every distinct color of the flattened image, in the order they first
+/// appear scanning from the top left.
+pub fn extract_palette(image: &Image) -> Palette {
+    let mut palette = Palette::new("Extracted");
+    let mut seen = std::collections::HashSet::new();
+    for &[r, g, b, a] in &image.flatten().data {
+        if a != 0 && seen.insert([r, g, b]) {
+            palette.add(Color::from_rgba(r, g, b, 255));
+        }
+    }
+    palette
+}
+
+/// Builds a palette of at most `colors` colors from the flattened image,
+/// then remaps every layer to it.
+pub fn quantize(image: &mut Image, colors: usize, method: QuantizeMethod, dither: Dither) -> Palette {
+    let histogram = histogram(&image.flatten());
+    let palette = build_palette(&histogram, colors, method);
+    for layer in &mut image.layers {
+        remap_layer(layer, &palette, dither);
+    }
+    palette
+}
+
+pub fn build_palette(histogram: &Histogram, colors: usize, method: QuantizeMethod) -> Palette {
+    let entries: Vec<([u8; 3], u32)> = histogram.iter().map(|(c, n)| (*c, *n)).collect();
+    let colors = match method {
+        QuantizeMethod::MedianCut => median_cut(entries, colors),
+        QuantizeMethod::KMeans => k_means(&entries, median_cut(entries.clone(), colors), 16),
+        QuantizeMethod::Octree => octree(&entries, colors),
+    };
+    let mut palette = Palette::new("Quantized");
+    for [r, g, b] in colors {
+        palette.add(Color::from_rgba(r, g, b, 255));
+    }
+    palette
+}
+
+fn weighted_mean(entries: &[([u8; 3], u32)]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    let mut count = 0u64;
+    for (color, n) in entries {
+        for c in 0..3 {
+            sum[c] += color[c] as u64 * *n as u64;
+        }
+        count += *n as u64;
+    }
+    let count = count.max(1);
+    sum.map(|s| ((s + count / 2) / count) as u8)
+}
+
+/// The channel with the widest range in `entries`, and that range.
+fn widest_channel(entries: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3).map(|c| {
+        let min = entries.iter().map(|(color, _)| color[c]).min().unwrap_or(0);
+        let max = entries.iter().map(|(color, _)| color[c]).max().unwrap_or(0);
+        (c, max - min)
+    }).max_by_key(|&(_, range)| range).unwrap()
+}
+
+fn median_cut(entries: Vec<([u8; 3], u32)>, colors: usize) -> Vec<[u8; 3]> {
+    if entries.is_empty() || colors == 0 {
+        return Vec::new();
+    }
+    let mut boxes = vec![entries];
+    while boxes.len() < colors {
+        let Some((i, channel)) = boxes.iter().enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .max_by_key(|&(_, (_, range))| range)
+            .map(|(i, (channel, _))| (i, channel))
+        else {
+            break;
+        };
+        let mut entries = boxes.swap_remove(i);
+        entries.sort_by_key(|(color, _)| color[channel]);
+        // Split at the pixel-weighted median, keeping at least one color on
+        // each side
+        let total: u64 = entries.iter().map(|(_, n)| *n as u64).sum();
+        let mut seen = 0;
+        let mut split = 1;
+        for (j, (_, n)) in entries.iter().enumerate() {
+            seen += *n as u64;
+            if seen * 2 >= total {
+                split = (j + 1).clamp(1, entries.len() - 1);
+                break;
+            }
+        }
+        let upper = entries.split_off(split);
+        boxes.push(entries);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| weighted_mean(b)).collect()
+}
+
+fn distance_sq(a: [u8; 3], b: [u8; 3]) -> u32 {
+    (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2) as u32).sum()
+}
+
+fn k_means(entries: &[([u8; 3], u32)], mut centers: Vec<[u8; 3]>, iterations: usize) -> Vec<[u8; 3]> {
+    for _ in 0..iterations {
+        let mut clusters = vec![Vec::new(); centers.len()];
+        for &(color, n) in entries {
+            let nearest = (0..centers.len()).min_by_key(|&i| distance_sq(color, centers[i])).unwrap();
+            clusters[nearest].push((color, n));
+        }
+        let moved: Vec<[u8; 3]> = clusters.iter().zip(&centers)
+            .map(|(cluster, center)| if cluster.is_empty() { *center } else { weighted_mean(cluster) })
+            .collect();
+        if moved == centers {
+            break;
+        }
+        centers = moved;
+    }
+    centers
+}
+
+#[derive(Default)]
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    sum: [u64; 3],
+    count: u64,
+    leaf: bool,
+}
+
+fn octree(entries: &[([u8; 3], u32)], colors: usize) -> Vec<[u8; 3]> {
+    if entries.is_empty() || colors == 0 {
+        return Vec::new();
+    }
+    let mut nodes = vec![OctreeNode::default()];
+    // Nodes at each depth that have children, to merge deepest first
+    let mut levels: Vec<Vec<usize>> = vec![Vec::new(); 8];
+    let mut leaves = 0;
+    for &(color, n) in entries {
+        let mut node = 0;
+        for (depth, level) in levels.iter_mut().enumerate() {
+            let shift = 7 - depth;
+            let child = ((color[0] >> shift & 1) << 2 | (color[1] >> shift & 1) << 1 | (color[2] >> shift & 1)) as usize;
+            node = match nodes[node].children[child] {
+                Some(next) => next,
+                None => {
+                    if nodes[node].children.iter().all(Option::is_none) {
+                        level.push(node);
+                    }
+                    nodes.push(OctreeNode::default());
+                    let next = nodes.len() - 1;
+                    nodes[node].children[child] = Some(next);
+                    next
+                }
+            };
+        }
+        let leaf = &mut nodes[node];
+        if !leaf.leaf {
+            leaf.leaf = true;
+            leaves += 1;
+        }
+        for (sum, &channel) in leaf.sum.iter_mut().zip(&color) {
+            *sum += channel as u64 * n as u64;
+        }
+        leaf.count += n as u64;
+    }
+
+    // Fold the children of the deepest, least used nodes into them until
+    // there are few enough leaves
+    for depth in (0..8).rev() {
+        let mut level = std::mem::take(&mut levels[depth]);
+        level.sort_by_key(|&i| std::cmp::Reverse(subtree_count(&nodes, i)));
+        while leaves > colors {
+            let Some(node) = level.pop() else {
+                break;
+            };
+            let mut sum = [0; 3];
+            let mut count = 0;
+            let mut merged = 0;
+            for child in nodes[node].children {
+                let Some(child) = child else {
+                    continue;
+                };
+                let child = &mut nodes[child];
+                for (sum, child_sum) in sum.iter_mut().zip(child.sum) {
+                    *sum += child_sum;
+                }
+                count += child.count;
+                child.leaf = false;
+                merged += 1;
+            }
+            nodes[node] = OctreeNode { children: [None; 8], sum, count, leaf: true };
+            leaves = leaves + 1 - merged;
+        }
+        if leaves <= colors {
+            break;
+        }
+    }
+
+    nodes.iter()
+        .filter(|node| node.leaf)
+        .map(|node| node.sum.map(|s| ((s + node.count / 2) / node.count) as u8))
+        .collect()
+}
+
+fn subtree_count(nodes: &[OctreeNode], node: usize) -> u64 {
+    nodes[node].count + nodes[node].children.iter().flatten().map(|&c| subtree_count(nodes, c)).sum::<u64>()
+}
+
+/// The palette's colors as RGB bytes, for `nearest_color`.
+pub fn palette_rgb(palette: &Palette) -> Vec<[u8; 3]> {
+    palette.colors.iter().map(|c| {
+        let [r, g, b, _] = color_to_rgba8(*c);
+        [r, g, b]
+    }).collect()
+}
+
+/// The index of the palette color closest to `color`.
+pub fn nearest_color(palette: &[[u8; 3]], color: [u8; 3]) -> usize {
+    (0..palette.len()).min_by_key(|&i| distance_sq(color, palette[i])).unwrap_or(0)
+}
+
+/// Replaces every pixel's color with one from the palette, keeping its alpha.
+/// Fully transparent pixels are left alone.
+pub fn remap_layer(layer: &mut Layer, palette: &Palette, dither: Dither) {
+    if palette.colors.is_empty() {
+        return;
+    }
+    let targets = palette_rgb(palette);
+    let (w, h) = (layer.rect.w as usize, layer.rect.h as usize);
+    let mut cache = HashMap::new();
+    // Floyd-Steinberg error carried into the current and next rows
+    let mut error = vec![[0.0f32; 3]; w * 2];
+
+    for y in 0..h {
+        for x in 0..w {
+            let i = y * w + x;
+            let pixel = layer.data[i];
+            if pixel[3] == 0 {
+                continue;
+            }
+            let wanted = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+            let wanted = match dither {
+                Dither::None => wanted,
+                Dither::FloydSteinberg => {
+                    let e = error[(y % 2) * w + x];
+                    [wanted[0] + e[0], wanted[1] + e[1], wanted[2] + e[2]]
+                }
+                Dither::Ordered => {
+                    // Spread by roughly the gap between palette colors
+                    let offset = (bayer_threshold(x as i32, y as i32) - 0.5) * 64.0;
+                    wanted.map(|c| c + offset)
+                }
+            };
+            let key = wanted.map(|c| c.round().clamp(0.0, 255.0) as u8);
+            let index = *cache.entry(key).or_insert_with(|| nearest_color(&targets, key));
+            let [r, g, b] = targets[index];
+
+            if dither == Dither::FloydSteinberg {
+                let e = [wanted[0] - r as f32, wanted[1] - g as f32, wanted[2] - b as f32];
+                let this_row = (y % 2) * w;
+                let next_row = ((y + 1) % 2) * w;
+                let mut spread = |i: usize, weight: f32| {
+                    for c in 0..3 {
+                        error[i][c] += e[c] * weight;
+                    }
+                };
+                if x + 1 < w {
+                    spread(this_row + x + 1, 7.0 / 16.0);
+                    spread(next_row + x + 1, 1.0 / 16.0);
+                }
+                if x > 0 {
+                    spread(next_row + x - 1, 3.0 / 16.0);
+                }
+                spread(next_row + x, 5.0 / 16.0);
+            }
+
+            layer.set_pixel(x as u32, y as u32, [r, g, b, pixel[3]]);
+        }
+        // The row just finished becomes the one after next
+        error[(y % 2) * w..(y % 2) * w + w].iter_mut().for_each(|e| *e = [0.0; 3]);
+    }
+    layer.add_dirty_rect(ImageRect::new(0, 0, layer.rect.w, layer.rect.h));
+}
+
+/// Snaps the pixels inside `rect` that differ from `before`, the layer's
+/// data before the edit, to their nearest palette color. Alpha is kept.
+pub fn snap_changed(layer: &mut Layer, palette: &Palette, before: &TiledPixels, rect: ImageRect) {
+    if palette.colors.is_empty() || before.len() != layer.data.len() {
+        return;
+    }
+    let targets = palette_rgb(palette);
+    let rect = rect.intersection(ImageRect::new(0, 0, layer.rect.w, layer.rect.h));
+    let w = layer.rect.w as usize;
+    for y in rect.y..rect.y + rect.h as i32 {
+        for x in rect.x..rect.x + rect.w as i32 {
+            let i = y as usize * w + x as usize;
+            let [r, g, b, a] = layer.data[i];
+            if layer.data[i] == before[i] || a == 0 {
+                continue;
+            }
+            let [r, g, b] = targets[nearest_color(&targets, [r, g, b])];
+            layer.set_pixel(x as u32, y as u32, [r, g, b, a]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::depth::BitDepth;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn noise_image(seed: u64) -> Image {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut image = Image::new(32, 32);
+        let mut layer = Layer::new(ImageRect::new(0, 0, 32, 32));
+        for y in 0..32 {
+            for x in 0..32 {
+                layer.data.set(x, y, [rng.gen(), rng.gen(), rng.gen(), 255]);
+            }
+        }
+        image.layers.push(layer);
+        image
+    }
+
+    #[test]
+    fn extracts_unique_colors_in_order() {
+        let mut image = Image::new(4, 1);
+        let mut layer = Layer::new(ImageRect::new(0, 0, 4, 1));
+        layer.draw_line(1, 0, 3, 0, Color::from_rgba(255, 0, 0, 255));
+        layer.draw_pixel(2, 0, Color::from_rgba(0, 0, 255, 255));
+        image.layers.push(layer);
+        let colors: Vec<[u8; 4]> = extract_palette(&image).colors.into_iter().map(color_to_rgba8).collect();
+        assert_eq!(colors, vec![[255, 255, 255, 255], [255, 0, 0, 255], [0, 0, 255, 255]]);
+    }
+
+    #[test]
+    fn every_method_reduces_to_n_colors() {
+        for method in [QuantizeMethod::MedianCut, QuantizeMethod::KMeans, QuantizeMethod::Octree] {
+            for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+                let mut image = noise_image(1);
+                let palette = quantize(&mut image, 16, method, dither);
+                assert!(palette.colors.len() <= 16 && palette.colors.len() >= 8, "{:?} made {} colors", method, palette.colors.len());
+                assert!(extract_palette(&image).colors.iter().all(|c| palette.colors.contains(c)));
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_existing_colors_when_there_are_few_enough() {
+        let mut image = Image::new(8, 8);
+        image.layers[0].draw_line(0, 0, 7, 7, Color::from_rgba(10, 200, 30, 255));
+        let before = image.layers[0].data.clone();
+        for method in [QuantizeMethod::MedianCut, QuantizeMethod::KMeans, QuantizeMethod::Octree] {
+            quantize(&mut image, 4, method, Dither::FloydSteinberg);
+            assert!(image.layers[0].data == before, "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn remapping_updates_sixteen_bit_channels() {
+        let mut image = Image::new(2, 1);
+        image.set_bit_depth(BitDepth::Sixteen);
+        image.layers[0].draw_pixel(1, 0, Color::new(0.2, 0.2, 0.2, 1.0));
+        let mut palette = Palette::new("bw");
+        palette.colors = vec![Color::from_rgba(0, 0, 0, 255), Color::from_rgba(255, 255, 255, 255)];
+        remap_layer(&mut image.layers[0], &palette, Dither::None);
+        assert_eq!(image.layers[0].deep.iter().copied().collect::<Vec<_>>(), vec![[65535; 4], [0, 0, 0, 65535]]);
+    }
+}
@@ -0,0 +1,134 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use super::app::Color;
+use super::layer::{Layer, ImageRect};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SprayDistribution {
+    Uniform,
+    Gaussian,
+}
+
+pub struct SprayCan {
+    pub radius: f32,
+    /// Fraction of the spray area painted by each burst.
+    pub density: f32,
+    /// Bursts per second while the mouse is held down.
+    pub flow: f32,
+    pub distribution: SprayDistribution,
+    rng: StdRng,
+    pending: f32,
+}
+
+impl Default for SprayCan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SprayCan {
+    pub fn new() -> Self {
+        Self::with_seed(rand::random())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            radius: 50.0,
+            density: 0.02,
+            flow: 30.0,
+            distribution: SprayDistribution::Uniform,
+            rng: StdRng::seed_from_u64(seed),
+            pending: 0.0,
+        }
+    }
+
+    /// Call this whenever a new stroke begins, so that the first frame of the
+    /// stroke always sprays a burst.
+    pub fn begin(&mut self) {
+        self.pending = 1.0;
+    }
+
+    /// Sprays around (x, y) for `dt` seconds' worth of flow.
+    pub fn spray(&mut self, layer: &mut Layer, x: i32, y: i32, color: Color, dt: f32) {
+        self.pending += dt * self.flow;
+        let bursts = self.pending.floor();
+        self.pending -= bursts;
+
+        let area = std::f32::consts::PI * self.radius * self.radius;
+        let dots_per_burst = (area * self.density).round().max(1.0) as u32;
+
+        for _ in 0..bursts as u32 * dots_per_burst {
+            let (dx, dy) = self.sample();
+            layer.draw_pixel(x + dx.round() as i32, y + dy.round() as i32, color);
+        }
+
+        if bursts > 0.0 {
+            let r = self.radius.ceil() as i32 + 1;
+            layer.add_dirty_rect(ImageRect::new(x - r, y - r, r as u32 * 2 + 1, r as u32 * 2 + 1));
+        }
+    }
+
+    /// Returns an offset from the center of the spray, always inside the
+    /// radius.
+    fn sample(&mut self) -> (f32, f32) {
+        match self.distribution {
+            SprayDistribution::Uniform => {
+                let r = self.radius * self.rng.gen::<f32>().sqrt();
+                let theta = self.rng.gen::<f32>() * std::f32::consts::TAU;
+                (r * theta.cos(), r * theta.sin())
+            }
+            SprayDistribution::Gaussian => {
+                // Box-Muller, with samples beyond the radius rejected
+                let sigma = self.radius / 2.0;
+                loop {
+                    let u1 = 1.0 - self.rng.gen::<f32>();
+                    let u2 = self.rng.gen::<f32>();
+                    let r = sigma * (-2.0 * u1.ln()).sqrt();
+                    if r <= self.radius {
+                        let theta = u2 * std::f32::consts::TAU;
+                        return (r * theta.cos(), r * theta.sin());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app;
+
+    fn spray_with_seed(seed: u64, distribution: SprayDistribution) -> Layer {
+        let mut layer = Layer::new(ImageRect::new(0, 0, 64, 64));
+        let mut spray = SprayCan::with_seed(seed);
+        spray.radius = 10.0;
+        spray.distribution = distribution;
+        spray.begin();
+        spray.spray(&mut layer, 32, 32, app::BLACK, 0.5);
+        layer
+    }
+
+    #[test]
+    fn same_seed_sprays_same_pixels() {
+        for &distribution in &[SprayDistribution::Uniform, SprayDistribution::Gaussian] {
+            let a = spray_with_seed(7, distribution);
+            let b = spray_with_seed(7, distribution);
+            assert!(a.data == b.data);
+        }
+    }
+
+    #[test]
+    fn dots_stay_inside_radius() {
+        let layer = spray_with_seed(3, SprayDistribution::Gaussian);
+        for y in 0..64 {
+            for x in 0..64 {
+                if layer.get_pixel(x, y) == Some(app::BLACK) {
+                    let (dx, dy) = ((x - 32) as f32, (y - 32) as f32);
+                    assert!((dx * dx + dy * dy).sqrt() <= 11.0);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use super::app::Color;
+use super::layer::{Layer, ImageRect};
+
+/// The pixels most recently painted by a freehand stroke, along with the
+/// colors they covered, so that pixel-perfect mode can take back the corner
+/// pixel of an L-shaped step after the fact.
+#[derive(Default)]
+pub struct Stroke {
+    pub pixel_perfect: bool,
+    tail: Vec<(i32, i32, Option<Color>)>,
+}
+
+impl Stroke {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the current stroke. Call this whenever a new stroke begins.
+    pub fn clear(&mut self) {
+        self.tail.clear();
+    }
+
+    /// Paints every pixel on the line from (x1, y1) to (x2, y2) as part of the
+    /// stroke.
+    pub fn draw_line(&mut self, layer: &mut Layer, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+        for (x, y) in super::layer::line_points(x1, y1, x2, y2) {
+            self.add_point(layer, x, y, color);
+        }
+    }
+
+    /// Paints a single stroke pixel. In pixel-perfect mode, a previous pixel
+    /// that forms an L-shaped corner with its neighbors is restored to its
+    /// original color.
+    pub fn add_point(&mut self, layer: &mut Layer, x: i32, y: i32, color: Color) {
+        if let Some(&(last_x, last_y, _)) = self.tail.last() {
+            if last_x == x && last_y == y {
+                return;
+            }
+        }
+
+        if self.pixel_perfect && self.tail.len() >= 2 {
+            let (ax, ay, _) = self.tail[self.tail.len() - 2];
+            let (bx, by, original) = self.tail[self.tail.len() - 1];
+            let a_to_b = (bx - ax).abs() + (by - ay).abs();
+            let b_to_p = (x - bx).abs() + (y - by).abs();
+            if a_to_b == 1 && b_to_p == 1 && (x - ax).abs() == 1 && (y - ay).abs() == 1 {
+                if let Some(original) = original {
+                    layer.draw_pixel(bx, by, original);
+                }
+                self.tail.pop();
+            }
+        }
+
+        self.tail.push((x, y, layer.get_pixel(x, y)));
+        if self.tail.len() > 2 {
+            self.tail.remove(0);
+        }
+        layer.draw_pixel(x, y, color);
+        layer.add_dirty_rect(ImageRect::new(x - 1, y - 1, 3, 3));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::app;
+
+    #[test]
+    fn pixel_perfect_removes_corner() {
+        let mut layer = Layer::new(ImageRect::new(0, 0, 4, 4));
+        let mut stroke = Stroke::new();
+        stroke.pixel_perfect = true;
+        stroke.draw_line(&mut layer, 0, 0, 1, 0, app::BLACK);
+        stroke.draw_line(&mut layer, 1, 0, 1, 1, app::BLACK);
+        assert_eq!(layer.get_pixel(0, 0), Some(app::BLACK));
+        assert_eq!(layer.get_pixel(1, 0), Some(app::WHITE));
+        assert_eq!(layer.get_pixel(1, 1), Some(app::BLACK));
+    }
+
+    /// A layer where every pixel starts out a different color, so a corner
+    /// restored to the wrong color shows up.
+    fn numbered_layer() -> Layer {
+        let mut layer = Layer::new(ImageRect::new(0, 0, 16, 16));
+        for y in 0..16 {
+            for x in 0..16 {
+                layer.set_pixel(x, y, [x as u8 * 10, y as u8 * 10, 100, 255]);
+            }
+        }
+        layer
+    }
+
+    /// Adds each point in turn to a pixel-perfect stroke on a numbered layer,
+    /// and returns the pixels left painted in row-major order. Every other
+    /// pixel must still have its own color.
+    fn stroke_points(points: &[(i32, i32)]) -> Vec<(i32, i32)> {
+        let mut layer = numbered_layer();
+        let mut stroke = Stroke::new();
+        stroke.pixel_perfect = true;
+        for &(x, y) in points {
+            stroke.add_point(&mut layer, x, y, app::BLACK);
+        }
+        let mut painted = Vec::new();
+        for y in 0..16 {
+            for x in 0..16 {
+                if layer.get_pixel(x, y) == Some(app::BLACK) {
+                    painted.push((x, y));
+                } else {
+                    assert_eq!(layer.data.get(x as u32, y as u32), [x as u8 * 10, y as u8 * 10, 100, 255], "({}, {})", x, y);
+                }
+            }
+        }
+        painted
+    }
+
+    #[test]
+    fn long_staircase_becomes_a_diagonal() {
+        // Right, down, right, down... as a mouse dragged diagonally would
+        let mut points = vec![(0, 0)];
+        for i in 1..=12 {
+            points.push((i, i - 1));
+            points.push((i, i));
+        }
+        assert_eq!(stroke_points(&points), (0..=12).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn lines_without_corners_are_left_alone() {
+        let points = super::super::layer::line_points(0, 0, 15, 6);
+        let mut expected = points.clone();
+        expected.sort_by_key(|&(x, y)| (y, x));
+        assert_eq!(stroke_points(&points), expected);
+    }
+
+    #[test]
+    fn reversing_keeps_the_pixels_already_painted() {
+        // Back over the same row, then a corner on a pixel painted on the way out
+        let points = [(0, 0), (1, 0), (2, 0), (3, 0), (2, 0), (1, 0), (1, 1)];
+        assert_eq!(stroke_points(&points), vec![(0, 0), (1, 0), (2, 0), (3, 0), (1, 1)]);
+
+        // Right, down, back up and back left, which takes back the second visit
+        let points = [(0, 0), (1, 0), (1, 1), (1, 0), (0, 0)];
+        assert_eq!(stroke_points(&points), vec![(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn corners_at_either_end_are_removed() {
+        let points = [(0, 0), (0, 1), (1, 1), (2, 1), (3, 1), (3, 2)];
+        assert_eq!(stroke_points(&points), vec![(0, 0), (1, 1), (2, 1), (3, 2)]);
+
+        // The very last point still takes back the corner before it
+        let points = [(0, 0), (1, 0), (2, 0), (2, 1)];
+        assert_eq!(stroke_points(&points), vec![(0, 0), (1, 0), (2, 1)]);
+
+        // But with nothing after it, the last point itself stays
+        let points = [(0, 0), (1, 0)];
+        assert_eq!(stroke_points(&points), vec![(0, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn corners_are_not_taken_back_across_strokes() {
+        let mut layer = numbered_layer();
+        let mut stroke = Stroke::new();
+        stroke.pixel_perfect = true;
+        stroke.draw_line(&mut layer, 0, 0, 1, 0, app::BLACK);
+        stroke.clear();
+        stroke.draw_line(&mut layer, 1, 1, 2, 1, app::BLACK);
+        for (x, y) in [(0, 0), (1, 0), (1, 1), (2, 1)] {
+            assert_eq!(layer.get_pixel(x, y), Some(app::BLACK));
+        }
+    }
+}
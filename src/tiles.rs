@@ -0,0 +1,373 @@
+use rayon::prelude::*;
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+use super::layer::{ImageRect, Rgba8, TRANSPARENT};
+
+/// The side of a tile in pixels.
+pub const TILE_SIZE: u32 = 64;
+const TILE_PIXELS: usize = (TILE_SIZE * TILE_SIZE) as usize;
+
+type Tile<T> = Arc<[T; TILE_PIXELS]>;
+
+/// The pixels of a layer, kept as a grid of square tiles that are only
+/// allocated once written to. Tiles that were never written read as
+/// `background`.
+///
+/// Cloning shares every tile, and a tile is only copied when one of the
+/// clones writes to it, so snapshots and duplicate layers are cheap.
+///
+/// Pixels are bytes by default, but any per-pixel value can be tiled, such as
+/// a layer's 16-bit channels or palette indices. The default value is an
+/// empty grid.
+#[derive(Clone, Default)]
+pub struct TiledPixels<T = Rgba8> {
+    w: u32,
+    h: u32,
+    columns: u32,
+    tiles: Vec<Option<Tile<T>>>,
+    background: T,
+}
+
+impl<T: Copy + PartialEq + Send + Sync> TiledPixels<T> {
+    pub fn new(w: u32, h: u32, background: T) -> Self {
+        let columns = w.div_ceil(TILE_SIZE);
+        Self {
+            w,
+            h,
+            columns,
+            tiles: vec![None; columns as usize * h.div_ceil(TILE_SIZE) as usize],
+            background,
+        }
+    }
+
+    /// Builds tiles from row-major pixels. Tiles where every pixel is
+    /// `background` stay unallocated.
+    pub fn from_pixels<I: IntoIterator<Item = T>>(w: u32, h: u32, background: T, pixels: I) -> Self {
+        let mut tiled = Self::new(w, h, background);
+        let mut count = 0;
+        for (i, pixel) in pixels.into_iter().enumerate() {
+            let (x, y) = tiled.position(i);
+            tiled.set(x, y, pixel);
+            count = i + 1;
+        }
+        assert_eq!(count, tiled.len(), "pixel count doesn't match the size");
+        tiled
+    }
+
+    /// A copy with `f` applied to every pixel. Only allocated tiles are
+    /// mapped pixel by pixel, so unallocated ones stay that way.
+    pub fn map<U: Copy + PartialEq + Send + Sync, F: Fn(T) -> U>(&self, f: F) -> TiledPixels<U> {
+        TiledPixels {
+            w: self.w,
+            h: self.h,
+            columns: self.columns,
+            tiles: self.tiles.iter().map(|tile| tile.as_ref().map(|pixels| Arc::new(pixels.map(&f)))).collect(),
+            background: f(self.background),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.w
+    }
+
+    pub fn height(&self) -> u32 {
+        self.h
+    }
+
+    /// The number of pixels, allocated or not.
+    pub fn len(&self) -> usize {
+        self.w as usize * self.h as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The index into `tiles` of the tile at column `tx` and row `ty`.
+    fn tile_index(&self, tx: u32, ty: u32) -> usize {
+        ty as usize * self.columns as usize + tx as usize
+    }
+
+    /// The (x, y) position of a row-major index.
+    fn position(&self, i: usize) -> (u32, u32) {
+        ((i % self.w as usize) as u32, (i / self.w as usize) as u32)
+    }
+
+    /// The tile holding (x, y) and the pixel's offset within it.
+    fn locate(&self, x: u32, y: u32) -> (usize, usize) {
+        let tile = self.tile_index(x / TILE_SIZE, y / TILE_SIZE);
+        let offset = (y % TILE_SIZE * TILE_SIZE + x % TILE_SIZE) as usize;
+        (tile, offset)
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> T {
+        let (tile, offset) = self.locate(x, y);
+        match &self.tiles[tile] {
+            Some(pixels) => pixels[offset],
+            None => self.background,
+        }
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, pixel: T) {
+        let (tile, _) = self.locate(x, y);
+        if self.tiles[tile].is_none() && pixel == self.background {
+            return;
+        }
+        *self.get_mut(x, y) = pixel;
+    }
+
+    /// Allocates the pixel's tile, or copies it if it's shared.
+    fn get_mut(&mut self, x: u32, y: u32) -> &mut T {
+        let (tile, offset) = self.locate(x, y);
+        let background = self.background;
+        let pixels = self.tiles[tile].get_or_insert_with(|| Arc::new([background; TILE_PIXELS]));
+        &mut Arc::make_mut(pixels)[offset]
+    }
+
+    /// Sets every pixel to `pixel`, freeing all the tiles.
+    pub fn fill(&mut self, pixel: T) {
+        self.tiles.iter_mut().for_each(|tile| *tile = None);
+        self.background = pixel;
+    }
+
+    /// The part of `rect` covered by the tile at column `tx` and row `ty`.
+    fn tile_part(tx: u32, ty: u32, rect: ImageRect) -> ImageRect {
+        ImageRect::new((tx * TILE_SIZE) as i32, (ty * TILE_SIZE) as i32, TILE_SIZE, TILE_SIZE).intersection(rect)
+    }
+
+    /// Calls `paint` in parallel with each tile that overlaps `rect` and the
+    /// part of `rect` it covers, so it can change those pixels. Tiles for
+    /// which `wanted` returns false are left alone, and stay unallocated if
+    /// they were.
+    ///
+    /// Each tile is painted by exactly one call, so the result doesn't depend
+    /// on how the work is split between threads.
+    pub fn par_paint<W, P>(&mut self, rect: ImageRect, wanted: W, paint: P)
+    where
+        W: Fn(ImageRect) -> bool,
+        P: Fn(ImageRect, &mut TileMut<T>) + Sync,
+    {
+        let rect = rect.intersection(ImageRect::new(0, 0, self.w, self.h));
+        if rect.w == 0 || rect.h == 0 {
+            return;
+        }
+        let (left, top) = (rect.x as u32 / TILE_SIZE, rect.y as u32 / TILE_SIZE);
+        let (right, bottom) = ((rect.x as u32 + rect.w - 1) / TILE_SIZE, (rect.y as u32 + rect.h - 1) / TILE_SIZE);
+        let mut parts = vec![None; self.tiles.len()];
+        for ty in top..=bottom {
+            for tx in left..=right {
+                let part = Self::tile_part(tx, ty, rect);
+                if wanted(part) {
+                    let i = self.tile_index(tx, ty);
+                    // Allocate or unshare it here, so every tile painted below
+                    // is uniquely owned
+                    let background = self.background;
+                    Arc::make_mut(self.tiles[i].get_or_insert_with(|| Arc::new([background; TILE_PIXELS])));
+                    parts[i] = Some(part);
+                }
+            }
+        }
+        let columns = self.columns as usize;
+        self.tiles.par_iter_mut().zip(parts).enumerate().for_each(|(i, (tile, part))| {
+            let (Some(part), Some(pixels)) = (part, tile) else {
+                return;
+            };
+            let mut tile = TileMut {
+                x: (i % columns) as u32 * TILE_SIZE,
+                y: (i / columns) as u32 * TILE_SIZE,
+                pixels: Arc::get_mut(pixels).expect("tile was unshared"),
+            };
+            paint(part, &mut tile);
+        });
+    }
+
+    /// The number of allocated tiles.
+    pub fn allocated_tiles(&self) -> usize {
+        self.tiles.iter().filter(|tile| tile.is_some()).count()
+    }
+
+    /// Iterates over every pixel in row-major order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (0..self.len()).map(move |i| &self[i])
+    }
+}
+
+impl TiledPixels {
+    /// Builds tiles from row-major pixels. Tiles that are entirely
+    /// transparent stay unallocated.
+    pub fn from_vec(w: u32, h: u32, pixels: Vec<Rgba8>) -> Self {
+        let pixels = pixels.into_iter().map(|pixel| if pixel[3] == 0 { TRANSPARENT } else { pixel });
+        Self::from_pixels(w, h, TRANSPARENT, pixels)
+    }
+
+    /// Whether the tile at column `tx` and row `ty` of the grid was never
+    /// written and reads as fully transparent, so compositing can skip it.
+    pub fn is_clear_tile(&self, tx: u32, ty: u32) -> bool {
+        self.tiles[self.tile_index(tx, ty)].is_none() && self.background[3] == 0
+    }
+
+    /// Whether every tile overlapping `rect` is clear.
+    pub fn is_clear(&self, rect: ImageRect) -> bool {
+        let rect = rect.intersection(ImageRect::new(0, 0, self.w, self.h));
+        if rect.w == 0 || rect.h == 0 {
+            return true;
+        }
+        let (left, top) = (rect.x as u32 / TILE_SIZE, rect.y as u32 / TILE_SIZE);
+        let (right, bottom) = ((rect.x as u32 + rect.w - 1) / TILE_SIZE, (rect.y as u32 + rect.h - 1) / TILE_SIZE);
+        (top..=bottom).all(|ty| (left..=right).all(|tx| self.is_clear_tile(tx, ty)))
+    }
+
+    /// The pixels as row-major RGBA bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.rect_bytes(ImageRect::new(0, 0, self.w, self.h))
+    }
+
+    /// The pixels inside `rect` as row-major RGBA bytes, converted a row per
+    /// task in parallel. `rect` must lie within the pixels.
+    pub fn rect_bytes(&self, rect: ImageRect) -> Vec<u8> {
+        let mut bytes = vec![0; rect.w as usize * rect.h as usize * 4];
+        if bytes.is_empty() {
+            return bytes;
+        }
+        bytes.par_chunks_mut(rect.w as usize * 4).enumerate().for_each(|(row, chunk)| {
+            let y = rect.y as u32 + row as u32;
+            for (x, pixel) in (rect.x as u32..).zip(chunk.chunks_exact_mut(4)) {
+                pixel.copy_from_slice(&self.get(x, y));
+            }
+        });
+        bytes
+    }
+}
+
+/// One tile handed out by `TiledPixels::par_paint`. Coordinates are those of
+/// the whole `TiledPixels`, and must lie inside the tile.
+pub struct TileMut<'a, T = Rgba8> {
+    x: u32,
+    y: u32,
+    pixels: &'a mut [T; TILE_PIXELS],
+}
+
+impl<T: Copy> TileMut<'_, T> {
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.pixels[((y - self.y) * TILE_SIZE + x - self.x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, pixel: T) {
+        self.pixels[((y - self.y) * TILE_SIZE + x - self.x) as usize] = pixel;
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync> Index<usize> for TiledPixels<T> {
+    type Output = T;
+
+    /// The pixel at a row-major index, as if the pixels were one `Vec`.
+    fn index(&self, i: usize) -> &T {
+        let (x, y) = self.position(i);
+        let (tile, offset) = self.locate(x, y);
+        match &self.tiles[tile] {
+            Some(pixels) => &pixels[offset],
+            None => &self.background,
+        }
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync> IndexMut<usize> for TiledPixels<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        let (x, y) = self.position(i);
+        self.get_mut(x, y)
+    }
+}
+
+impl<'a, T: Copy + PartialEq + Send + Sync> IntoIterator for &'a TiledPixels<T> {
+    type Item = &'a T;
+    type IntoIter = Box<dyn Iterator<Item = &'a T> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl<T: Copy + PartialEq + Send + Sync> PartialEq for TiledPixels<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.w == other.w && self.h == other.h && self.iter().eq(other.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_are_allocated_on_write() {
+        let mut pixels = TiledPixels::new(200, 100, [255; 4]);
+        assert_eq!(pixels.allocated_tiles(), 0);
+        assert_eq!(pixels.get(199, 99), [255; 4]);
+
+        pixels.set(130, 70, [1, 2, 3, 4]);
+        pixels[5] = [5, 6, 7, 8];
+        assert_eq!(pixels.allocated_tiles(), 2);
+        assert_eq!(pixels[70 * 200 + 130], [1, 2, 3, 4]);
+        assert_eq!(pixels.get(5, 0), [5, 6, 7, 8]);
+        assert_eq!(pixels.iter().filter(|&&p| p != [255; 4]).count(), 2);
+
+        pixels.fill([0; 4]);
+        assert_eq!(pixels.allocated_tiles(), 0);
+        assert!(pixels.is_clear_tile(2, 1));
+    }
+
+    #[test]
+    fn parallel_painting_only_touches_wanted_tiles() {
+        let mut pixels = TiledPixels::new(300, 200, [0; 4]);
+        let shared = pixels.clone();
+        pixels.par_paint(ImageRect::new(10, 10, 280, 100), |part| part.x < 128, |part, tile| {
+            for y in part.y as u32..part.y as u32 + part.h {
+                for x in part.x as u32..part.x as u32 + part.w {
+                    tile.set(x, y, [x as u8, y as u8, 0, 255]);
+                }
+            }
+        });
+        assert_eq!(pixels.allocated_tiles(), 4);
+        assert_eq!(pixels.get(127, 109), [127, 109, 0, 255]);
+        assert_eq!(pixels.get(128, 50), [0; 4]);
+        assert_eq!(pixels.get(9, 10), [0; 4]);
+        assert_eq!(shared.allocated_tiles(), 0);
+        assert_eq!(&pixels.rect_bytes(ImageRect::new(127, 109, 2, 1)), &[127, 109, 0, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn huge_sizes_dont_overflow() {
+        let mut pixels = TiledPixels::new(70000, 70000, [0; 4]);
+        assert_eq!(pixels.len(), 70000 * 70000);
+        pixels.set(69999, 69999, [7; 4]);
+        assert_eq!(pixels.get(69999, 69999), [7; 4]);
+        assert_eq!(pixels[70000 * 70000 - 1], [7; 4]);
+        assert_eq!(pixels.allocated_tiles(), 1);
+    }
+
+    #[test]
+    fn maps_only_allocated_tiles() {
+        let mut pixels = TiledPixels::new(130, 64, [0; 4]);
+        pixels.set(129, 3, [255, 0, 0, 255]);
+        let wide = pixels.map(|[r, g, b, a]| [r as u16 * 257, g as u16 * 257, b as u16 * 257, a as u16 * 257]);
+        assert_eq!(wide.allocated_tiles(), 1);
+        assert_eq!(wide.get(129, 3), [65535, 0, 0, 65535]);
+        assert_eq!(wide.get(0, 0), [0; 4]);
+
+        let indices = TiledPixels::from_pixels(3, 1, None, [None, Some(2u8), None]);
+        assert_eq!(indices.allocated_tiles(), 1);
+        assert_eq!(indices.iter().copied().collect::<Vec<_>>(), vec![None, Some(2), None]);
+        assert!(TiledPixels::<Option<u8>>::default().is_empty());
+    }
+
+    #[test]
+    fn clones_copy_tiles_on_write() {
+        let mut original = TiledPixels::new(64, 64, [0; 4]);
+        original.set(0, 0, [9; 4]);
+        let mut copy = original.clone();
+        copy.set(1, 0, [7; 4]);
+        assert_eq!(original.get(1, 0), [0; 4]);
+        assert_eq!(copy.get(0, 0), [9; 4]);
+        assert!(original != copy);
+    }
+}
@@ -0,0 +1,188 @@
+use super::depth::{self, BitDepth};
+use super::indexed;
+use super::layer::{Image, ImageRect, Layer, TRANSPARENT};
+use super::tiles::TiledPixels;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Flip {
+    /// Mirrors left to right.
+    Horizontal,
+    /// Mirrors top to bottom.
+    Vertical,
+}
+
+/// Maps pixel `i` of a row `to` pixels long back onto a row `from` pixels
+/// long. Nearest neighbor keeps pixel art crisp.
+fn nearest(i: u32, from: u32, to: u32) -> u32 {
+    (i as u64 * from as u64 / to as u64) as u32
+}
+
+/// Builds a `w` by `h` copy of `layer` at (x, y), taking each pixel from the
+/// source position `source` gives for it.
+fn remapped<F: Fn(u32, u32) -> (u32, u32)>(layer: &Layer, x: i32, y: i32, w: u32, h: u32, source: F) -> Layer {
+    let mut out = Layer::new(ImageRect::new(x, y, w, h));
+    out.data.fill(TRANSPARENT);
+    let keep_deep = !layer.deep.is_empty();
+    let keep_indices = !layer.indices.is_empty();
+    if keep_deep {
+        out.deep = TiledPixels::new(w, h, [0; 4]);
+    }
+    if keep_indices {
+        out.indices = TiledPixels::new(w, h, None);
+        out.palette = layer.palette.clone();
+    }
+    for ty in 0..h {
+        for tx in 0..w {
+            let (sx, sy) = source(tx, ty);
+            out.data.set(tx, ty, layer.data.get(sx, sy));
+            if keep_deep {
+                out.deep.set(tx, ty, layer.deep.get(sx, sy));
+            }
+            if keep_indices {
+                out.indices.set(tx, ty, layer.indices.get(sx, sy));
+            }
+        }
+    }
+    out.z_index = layer.z_index;
+    out.add_dirty_rect(ImageRect::new(0, 0, w, h));
+    out
+}
+
+impl Image {
+    /// Scales the image and every layer in it to `w` by `h` with nearest
+    /// neighbor sampling.
+    pub fn resize(&mut self, w: u32, h: u32) {
+        let (old_w, old_h) = (self.rect.w.max(1) as i64, self.rect.h.max(1) as i64);
+        let scale_x = |v: i64| (v * w as i64 / old_w) as i32;
+        let scale_y = |v: i64| (v * h as i64 / old_h) as i32;
+        for layer in &mut self.layers {
+            let r = layer.rect;
+            let (x, y) = (scale_x(r.x as i64), scale_y(r.y as i64));
+            let new_w = (scale_x(r.right() as i64) - x) as u32;
+            let new_h = (scale_y(r.bottom() as i64) - y) as u32;
+            *layer = remapped(layer, x, y, new_w, new_h, |tx, ty| (nearest(tx, r.w, new_w), nearest(ty, r.h, new_h)));
+        }
+        self.rect = ImageRect::new(self.rect.x, self.rect.y, w, h);
+    }
+
+    /// Mirrors the image, moving each layer to the mirrored position too.
+    pub fn flip(&mut self, flip: Flip) {
+        let image = self.rect;
+        for layer in &mut self.layers {
+            let r = layer.rect;
+            *layer = match flip {
+                Flip::Horizontal => remapped(layer, image.x + image.right() - r.right(), r.y, r.w, r.h, |tx, ty| (r.w - 1 - tx, ty)),
+                Flip::Vertical => remapped(layer, r.x, image.y + image.bottom() - r.bottom(), r.w, r.h, |tx, ty| (tx, r.h - 1 - ty)),
+            };
+        }
+    }
+
+    /// Replaces the layers with a single one, flattened over transparency.
+    /// 16-bit images keep their 16-bit channels, and indexed images stay
+    /// indexed.
+    pub fn flatten_layers(&mut self) {
+        let mut layer = self.flatten();
+        if self.is_16bit() {
+            layer.deep = TiledPixels::from_pixels(layer.rect.w, layer.rect.h, [0; 4], self.flatten_16bit([0; 4]));
+            layer.data = layer.deep.map(depth::narrow);
+        }
+        if let Some(palette) = &self.palette {
+            indexed::index_layer(&mut layer, palette);
+        }
+        layer.add_dirty_rect(ImageRect::new(0, 0, layer.rect.w, layer.rect.h));
+        self.layers = vec![layer];
+    }
+}
+
+/// Lays `frames` out left to right and top to bottom in a grid `columns`
+/// wide. Every cell is the size of the largest frame, and each frame is
+/// flattened into the top left of its cell. If any frame is 16-bit, so is the
+/// sheet, and the rest are widened to match.
+pub fn sprite_sheet(frames: &[Image], columns: u32) -> Image {
+    let columns = columns.max(1);
+    let cell_w = frames.iter().map(|f| f.rect.w).max().unwrap_or(0);
+    let cell_h = frames.iter().map(|f| f.rect.h).max().unwrap_or(0);
+    let rows = (frames.len() as u32).div_ceil(columns);
+    let cell = |i: usize| (i as u32 % columns * cell_w, i as u32 / columns * cell_h);
+    let mut sheet = Image::new(cell_w * columns.min(frames.len() as u32), cell_h * rows);
+    sheet.layers[0].data.fill(TRANSPARENT);
+    if frames.iter().any(Image::is_16bit) {
+        sheet.set_bit_depth(BitDepth::Sixteen);
+        let layer = &mut sheet.layers[0];
+        for (i, frame) in frames.iter().enumerate() {
+            let (x, y) = cell(i);
+            let w = frame.rect.w as usize;
+            for (j, &pixel) in frame.flatten_16bit([0; 4]).iter().enumerate() {
+                layer.deep.set(x + (j % w) as u32, y + (j / w) as u32, pixel);
+            }
+        }
+        layer.data = layer.deep.map(depth::narrow);
+        return sheet;
+    }
+    for (i, frame) in frames.iter().enumerate() {
+        let mut flat = frame.flatten();
+        let (x, y) = cell(i);
+        flat.rect.x = x as i32;
+        flat.rect.y = y as i32;
+        sheet.layers[0].composite_rect(&flat, flat.rect);
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x2 image with one marked pixel at (x, y).
+    fn marked_image(x: u32, y: u32) -> Image {
+        let mut image = Image::new(4, 2);
+        image.layers[0].data.set(x, y, [255, 0, 0, 255]);
+        image
+    }
+
+    fn marked_pixels(image: &Image) -> Vec<(u32, u32)> {
+        let layer = &image.layers[0];
+        let mut marked = Vec::new();
+        for y in 0..layer.rect.h {
+            for x in 0..layer.rect.w {
+                if layer.data.get(x, y) == [255, 0, 0, 255] {
+                    marked.push((x, y));
+                }
+            }
+        }
+        marked
+    }
+
+    #[test]
+    fn resizes_with_nearest_neighbor() {
+        let mut image = marked_image(1, 0);
+        image.resize(8, 6);
+        assert_eq!((image.rect.w, image.rect.h), (8, 6));
+        assert_eq!(marked_pixels(&image), vec![(2, 0), (3, 0), (2, 1), (3, 1), (2, 2), (3, 2)]);
+    }
+
+    #[test]
+    fn flips_layers_and_their_positions() {
+        let mut image = marked_image(0, 0);
+        let mut small = Layer::new(ImageRect::new(1, 0, 2, 1));
+        small.data.fill(TRANSPARENT);
+        image.layers.push(small);
+
+        image.flip(Flip::Horizontal);
+        assert_eq!(marked_pixels(&image), vec![(3, 0)]);
+        assert_eq!(image.layers[1].rect, ImageRect::new(1, 0, 2, 1));
+        image.flip(Flip::Vertical);
+        assert_eq!(marked_pixels(&image), vec![(3, 1)]);
+        assert_eq!(image.layers[1].rect, ImageRect::new(1, 1, 2, 1));
+    }
+
+    #[test]
+    fn packs_frames_into_a_grid() {
+        let frames = vec![marked_image(0, 0), marked_image(1, 1), marked_image(3, 0)];
+        let sheet = sprite_sheet(&frames, 2);
+        assert_eq!((sheet.rect.w, sheet.rect.h), (8, 4));
+        assert_eq!(marked_pixels(&sheet), vec![(0, 0), (5, 1), (3, 2)]);
+        // The unused cell stays clear
+        assert_eq!(sheet.layers[0].data.get(7, 3), TRANSPARENT);
+    }
+}
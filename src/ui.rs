@@ -1,5 +1,6 @@
 use super::app::{self as g, Key, Color, Rect, Vec2, Font};
 use crate::{color, rect, vec2};
+use super::util::lerp_color;
 
 // ============================================================
 
@@ -60,6 +61,20 @@ pub mod WidgetFlags {
     pub const MOVABLE: u64 = 1 << 3;
     pub const INVISIBLE: u64 = 1 << 4;
     pub const EDIT_TEXT: u64 = 1 << 5;
+    pub const DRAW_FILL: u64 = 1 << 6;
+}
+
+/// What a widget with `WidgetFlags::DRAW_FILL` paints in place of its
+/// background color.
+#[derive(Clone, Default)]
+pub enum WidgetFill {
+    #[default]
+    None,
+    /// Colors spread evenly from left to right.
+    HorizontalGradient(Vec<Color>),
+    /// Saturation from left to right and value from top to bottom, for the
+    /// given fully saturated hue.
+    SaturationValue(Color),
 }
 
 #[derive(Default)]
@@ -89,6 +104,8 @@ struct Widget {
 
     // Content
     content_str: String,
+    fill: WidgetFill,
+    marker: Option<Vec2>,
 }
 
 #[derive(Clone, Default)]
@@ -98,6 +115,9 @@ pub struct Interaction {
     pub dragging: bool,
     pub text_edited: bool,
     pub text: String,
+    /// While dragging, the mouse position relative to the widget, from (0, 0)
+    /// at the top left to (1, 1) at the bottom right.
+    pub drag_pos: Vec2,
 }
 
 #[derive(Default)]
@@ -135,6 +155,7 @@ pub struct Ui {
     next_floating_window_pos: Vec2,
     pub mouse_intercepted: bool,
     pub keyboard_intercepted: bool,
+    keyboard_window: usize,
     keyboard_id: usize,
 }
 
@@ -152,10 +173,13 @@ pub struct Window {
 
     mouse_intercepted: bool,
     zindex: usize,
+
+    // Windows that aren't pushed during a frame are hidden for that frame
+    visible: bool,
 }
 
 fn get_display_text(text: &str) -> &str {
-    if let Some(i) = text.rfind("##") {
+    if let Some(i) = text.find("##") {
         let (display_text, _) = text.split_at(i);
         display_text
     } else {
@@ -163,6 +187,17 @@ fn get_display_text(text: &str) -> &str {
     }
 }
 
+// Everything after "###" identifies the widget on its own, so the display text
+// in front of it can change from frame to frame.
+fn get_id_text(text: &str) -> &str {
+    if let Some(i) = text.find("###") {
+        let (_, id_text) = text.split_at(i);
+        id_text
+    } else {
+        text
+    }
+}
+
 fn measure_text(text: &str, style: &StyleInfo) -> g::TextDimensions {
     g::measure_text(text, style.font.as_ref(), style.font_size as u16, 1.0)
 }
@@ -178,6 +213,59 @@ fn draw_text(text: &str, x: f32, y: f32, style: &StyleInfo) {
     });
 }
 
+fn draw_fill(rect: Rect, fill: &WidgetFill) {
+    match fill {
+        WidgetFill::None => {}
+        WidgetFill::HorizontalGradient(colors) => {
+            if colors.iter().any(|c| c.a < 1.0) {
+                draw_checkerboard(rect);
+            }
+            if colors.len() < 2 {
+                if let Some(color) = colors.first() {
+                    g::draw_rect(rect, *color);
+                }
+                return;
+            }
+            let slices = 64;
+            let slice_w = rect.w / slices as f32;
+            for i in 0..slices {
+                let t = (i as f32 + 0.5) / slices as f32 * (colors.len() - 1) as f32;
+                let j = (t.floor() as usize).min(colors.len() - 2);
+                let color = lerp_color(colors[j], colors[j + 1], t - j as f32);
+                g::draw_rectangle(rect.x + i as f32 * slice_w, rect.y, slice_w + 0.5, rect.h, color);
+            }
+        }
+        WidgetFill::SaturationValue(hue) => {
+            let cells = 32;
+            let (cell_w, cell_h) = (rect.w / cells as f32, rect.h / cells as f32);
+            for cy in 0..cells {
+                let value = 1.0 - (cy as f32 + 0.5) / cells as f32;
+                for cx in 0..cells {
+                    let saturation = (cx as f32 + 0.5) / cells as f32;
+                    let top = lerp_color(g::WHITE, *hue, saturation);
+                    let color = Color::new(top.r * value, top.g * value, top.b * value, 1.0);
+                    g::draw_rectangle(rect.x + cx as f32 * cell_w, rect.y + cy as f32 * cell_h, cell_w + 0.5, cell_h + 0.5, color);
+                }
+            }
+        }
+    }
+}
+
+fn draw_checkerboard(rect: Rect) {
+    let size = 8.0;
+    let mut y = 0.0;
+    while y < rect.h {
+        let mut x = 0.0;
+        while x < rect.w {
+            let dark = ((x / size) as i32 + (y / size) as i32) % 2 == 0;
+            let color = if dark { color!(150, 150, 150) } else { color!(220, 220, 220) };
+            g::draw_rectangle(rect.x + x, rect.y + y, size.min(rect.w - x), size.min(rect.h - y), color);
+            x += size;
+        }
+        y += size;
+    }
+}
+
 fn edit_line(text: &mut String) -> bool {
     let mut text_edited = false;
 
@@ -352,6 +440,7 @@ impl Ui {
         let window = Window {
             name: "FIRST_ROOT_WINDOW".to_string(),
             styles: vec![style],
+            visible: true,
             ..Default::default()
         };
         ui.windows.push(window);
@@ -449,13 +538,14 @@ impl Ui {
 
         let mut target_id = None;
         for widget_id in &self.windows[w].widgets[self.windows[w].current_id].children {
-            if self.windows[w].widgets[*widget_id].name == widget.name {
+            if get_id_text(&self.windows[w].widgets[*widget_id].name) == get_id_text(&widget.name) {
                 target_id = Some(*widget_id);
             }
         }
 
         if let Some(id) = target_id {
             interaction = self.windows[w].widgets[id].interaction.clone();
+            self.windows[w].widgets[id].name = widget.name;
         } else {
             let mut widget = widget;
             widget.id = self.windows[w].widgets.len();
@@ -487,7 +577,7 @@ impl Ui {
 
             let color = if widget.hovered && (flags & WidgetFlags::CLICKABLE != 0) {
                 color!(128, 128, 128)
-            } else if self.keyboard_intercepted && id == self.keyboard_id && (flags & WidgetFlags::EDIT_TEXT != 0) {
+            } else if self.has_keyboard(window_id, id) && (flags & WidgetFlags::EDIT_TEXT != 0) {
                 color!(50, 50, 50)
             } else {
                 style.background_color
@@ -506,6 +596,16 @@ impl Ui {
                 g::draw_rect(widget.rect, color);
             }
 
+            if flags & WidgetFlags::DRAW_FILL != 0 {
+                draw_fill(widget.rect, &widget.fill);
+            }
+            if let Some(marker) = widget.marker {
+                let x = widget.rect.x + marker.x * widget.rect.w;
+                let y = widget.rect.y + marker.y * widget.rect.h;
+                g::draw_rectangle_lines(x - 4.0, y - 4.0, 8.0, 8.0, 2.0, g::BLACK);
+                g::draw_rectangle_lines(x - 3.0, y - 3.0, 6.0, 6.0, 1.0, g::WHITE);
+            }
+
             if flags & WidgetFlags::DRAW_TEXT != 0 {
                 let display_text = get_display_text(&widget.name);
                 draw_text(&display_text, widget.rect.x + style.padding, widget.rect.y + style.padding, &style);
@@ -555,13 +655,14 @@ impl Ui {
                     if flags & WidgetFlags::EDIT_TEXT != 0 {
                         //println!("EDIT_TEXT");
                         self.keyboard_intercepted = true;
+                        self.keyboard_window = w;
                         self.keyboard_id = id;
                     }
                 }
             }
         }
 
-        if (flags & WidgetFlags::EDIT_TEXT != 0) && self.keyboard_intercepted && self.keyboard_id == id {
+        if (flags & WidgetFlags::EDIT_TEXT != 0) && self.has_keyboard(w, id) {
             interaction.text_edited = edit_line(&mut self.windows[w].widgets[id].content_str);
             //if let Some(text) = g::get_text() {
             //    self.windows[w].widgets[id].content_str.push_str(&text);
@@ -573,6 +674,15 @@ impl Ui {
         if !g::is_mouse_left_down()  {
             interaction.dragging = false;
         }
+        if interaction.dragging {
+            // Keep the drag from reaching the canvas once it leaves the widget
+            self.mouse_intercepted = true;
+            let rect = self.windows[w].widgets[id].rect;
+            interaction.drag_pos = vec2!(
+                ((mouse_x - rect.x) / rect.w).clamp(0.0, 1.0),
+                ((mouse_y - rect.y) / rect.h).clamp(0.0, 1.0),
+            );
+        }
         interaction.text = self.windows[w].widgets[id].content_str.clone();
 
         self.windows[w].widgets[id].interaction = interaction;
@@ -644,6 +754,68 @@ impl Ui {
         interaction
     }
 
+    /// A draggable area painted with `fill`, with an optional marker at a
+    /// position given in the same 0..1 space as `Interaction::drag_pos`.
+    pub fn color_area(&mut self, name: &str, w: f32, h: f32, fill: WidgetFill, marker: Option<Vec2>) -> Interaction {
+        let window = self.current_id;
+        let id = self.windows[window].widgets.len();
+        let (id, interaction) = self.check_widget(Widget {
+            id,
+            name: name.to_string(),
+            size: [
+                Size::new(SizeKind::Pixels, w, 1.0),
+                Size::new(SizeKind::Pixels, h, 1.0),
+            ],
+            flags: WidgetFlags::CLICKABLE | WidgetFlags::MOVABLE | WidgetFlags::DRAW_FILL,
+            ..Default::default()
+        });
+        self.windows[window].widgets[id].fill = fill;
+        self.windows[window].widgets[id].marker = marker;
+        interaction
+    }
+
+    /// A text box that shows `text` whenever it isn't being edited, and writes
+    /// edits back to it. Returns true if `text` was edited.
+    pub fn edit_box(&mut self, name: &str, width: f32, text: &mut String) -> bool {
+        let w = self.current_id;
+        let id = self.windows[w].widgets.len();
+        let (id, interaction) = self.check_widget(Widget {
+            id,
+            name: name.to_string(),
+            size: [
+                Size::new(SizeKind::Pixels, width, 1.0),
+                Size::new(SizeKind::TextContent, 0.0, 1.0),
+            ],
+            flags: WidgetFlags::DRAW_BORDER | WidgetFlags::EDIT_TEXT | WidgetFlags::CLICKABLE,
+            ..Default::default()
+        });
+        if interaction.text_edited {
+            *text = interaction.text;
+            return true;
+        }
+        if !self.has_keyboard(w, id) {
+            self.windows[w].widgets[id].content_str = text.clone();
+        }
+        false
+    }
+
+    /// An `edit_box` for a whole number between `min` and `max`. Returns true
+    /// if `value` was changed.
+    pub fn number_box(&mut self, name: &str, value: &mut f32, min: f32, max: f32) -> bool {
+        let mut text = format!("{:.0}", value);
+        if self.edit_box(name, 60.0, &mut text) {
+            if let Ok(new_value) = text.trim().parse::<f32>() {
+                *value = new_value.clamp(min, max);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn has_keyboard(&self, window_id: usize, id: usize) -> bool {
+        self.keyboard_intercepted && self.keyboard_window == window_id && self.keyboard_id == id
+    }
+
     // ============================================================
 
     fn check_window(&mut self, window: Window) {
@@ -659,10 +831,12 @@ impl Ui {
 
         if let Some(id) = target_id {
             self.current_id = id;
+            self.windows[id].visible = true;
         } else {
             println!("NEW WINDOW");
             self.current_id = self.windows.len();
             self.windows.push(window);
+            self.windows[self.current_id].visible = true;
             self.windows[self.current_id].widgets.push(Widget {
                 name: "ANOTHER_ROOT_WIDGET".to_string(),
                 size: [
@@ -690,7 +864,11 @@ impl Ui {
 
         self.current_id = 0;
         self.mouse_intercepted = false;
-        //self.keyboard_intercepted = false;
+        // Clicking anywhere releases the keyboard, unless the click lands on
+        // a text box, which takes it back in calc_input
+        if g::is_mouse_left_pressed() {
+            self.keyboard_intercepted = false;
+        }
 
         // println!("========================================");
         self.windows[0].rect = rect!(0.0, 0.0, g::screen_width(), g::screen_height());
@@ -733,14 +911,23 @@ impl Ui {
         }
 
         for window_id in (0..self.windows.len()).rev() {
-            self.calc_input(window_id, 0, 0);
+            if self.windows[window_id].visible {
+                self.calc_input(window_id, 0, 0);
+            }
         }
 
         for window_id in 0..self.windows.len() {
+            if !self.windows[window_id].visible {
+                continue;
+            }
             for i in 0..self.windows[window_id].widgets.len() {
                 // println!("-------------------------------");
                 self.draw_node(window_id, 0, 0);
             }
         }
+
+        for window_id in 1..self.windows.len() {
+            self.windows[window_id].visible = false;
+        }
     }
 }
@@ -1,11 +1,138 @@
 #![allow(dead_code)]
-use std::cmp::{min, max};
+use std::fmt;
+use std::num::ParseIntError;
 
-use macroquad::color::Color;
+use super::app::Color;
 
-pub fn color_from_hex_str(s: &str) -> Result<Self, std::num::ParseIntError> {
-    // TODO might be able to use from_hex from macroquad, but make sure it doesn't have confusing
-    // behavior when you don't provide an alpha channel
+#[derive(Debug, PartialEq)]
+pub enum HexColorError {
+    /// Not 3, 4, 6 or 8 hex digits.
+    Length(usize),
+    Digit(ParseIntError),
+}
+
+impl fmt::Display for HexColorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HexColorError::Length(len) => write!(f, "expected 3, 4, 6 or 8 hex digits, got {}", len),
+            HexColorError::Digit(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ParseIntError> for HexColorError {
+    fn from(e: ParseIntError) -> Self {
+        HexColorError::Digit(e)
+    }
+}
+
+/// Parses "RGB", "RGBA", "RRGGBB" or "RRGGBBAA", with or without a leading
+/// '#'. Alpha is opaque when it isn't given.
+// Not macroquad's from_hex, which takes a u32 and can't tell a missing alpha
+// channel from a transparent one
+pub fn color_from_hex_str(s: &str) -> Result<Color, HexColorError> {
+    let s = s.trim();
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if !s.is_ascii() {
+        return Err(HexColorError::Length(s.chars().count()));
+    }
+    let channels = match s.len() {
+        3 | 4 => s.chars()
+            .map(|c| u8::from_str_radix(&c.to_string(), 16).map(|v| v * 17))
+            .collect::<Result<Vec<u8>, _>>()?,
+        6 | 8 => (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?,
+        len => return Err(HexColorError::Length(len)),
+    };
+    let a = channels.get(3).copied().unwrap_or(255);
+    Ok(Color::from_rgba(channels[0], channels[1], channels[2], a))
+}
+
+/// Rounds each channel to 0-255. Unlike macroquad's conversion, which
+/// truncates, this gives back exactly what `Color::from_rgba` was given.
+pub fn color_to_rgba8(color: Color) -> [u8; 4] {
+    [color.r, color.g, color.b, color.a].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// Formats as "RRGGBB", or "RRGGBBAA" if the color isn't opaque.
+pub fn color_to_hex(color: Color) -> String {
+    let [r, g, b, a] = color_to_rgba8(color);
+    if a == 255 {
+        format!("{:02X}{:02X}{:02X}", r, g, b)
+    } else {
+        format!("{:02X}{:02X}{:02X}{:02X}", r, g, b, a)
+    }
+}
+
+pub fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    Color::new(
+        a.r + (b.r - a.r) * t,
+        a.g + (b.g - a.g) * t,
+        a.b + (b.b - a.b) * t,
+        a.a + (b.a - a.a) * t,
+    )
+}
+
+/// Converts to hue in degrees, and saturation and value from 0.0 to 1.0.
+pub fn rgb_to_hsv(color: Color) -> (f32, f32, f32) {
+    let max = color.r.max(color.g).max(color.b);
+    let min = color.r.min(color.g).min(color.b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == color.r {
+        60.0 * ((color.g - color.b) / delta).rem_euclid(6.0)
+    } else if max == color.g {
+        60.0 * ((color.b - color.r) / delta + 2.0)
+    } else {
+        60.0 * ((color.r - color.g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32, alpha: f32) -> Color {
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let c = value * saturation;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = value - c;
+    Color::new(r + m, g + m, b + m, alpha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(color_from_hex_str("#FF8000"), Ok(Color::from_rgba(255, 128, 0, 255)));
+        assert_eq!(color_from_hex_str("ff800040"), Ok(Color::from_rgba(255, 128, 0, 64)));
+        assert_eq!(color_from_hex_str("#f80"), Ok(Color::from_rgba(255, 136, 0, 255)));
+        assert_eq!(color_from_hex_str("12345"), Err(HexColorError::Length(5)));
+        assert!(matches!(color_from_hex_str("GG0000"), Err(HexColorError::Digit(_))));
+        assert_eq!(color_to_hex(Color::from_rgba(255, 128, 0, 64)), "FF800040");
+    }
+
+    #[test]
+    fn hsv_round_trips() {
+        for &(r, g, b) in &[(255, 0, 0), (12, 200, 90), (30, 30, 30), (0, 0, 255), (255, 0, 128)] {
+            let color = Color::from_rgba(r, g, b, 255);
+            let (h, s, v) = rgb_to_hsv(color);
+            let back = hsv_to_rgb(h, s, v, 1.0);
+            for (a, b) in [(back.r, color.r), (back.g, color.g), (back.b, color.b)] {
+                assert!((a - b).abs() < 1e-5, "{:?} became {:?}", color, back);
+            }
+        }
+    }
 }
 
     // pub fn union(&self, other: Rect) -> Rect {